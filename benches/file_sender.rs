@@ -0,0 +1,50 @@
+//! Items/sec of `UnboundedFileSender` with different item sizes, run with `cargo bench`.
+use futures::stream::iter_ok;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{unbounded_file, Error, GroupCommit};
+
+const ITEM_SIZES: &[usize] = &[16, 256, 4 * 1024, 64 * 1024];
+// bytes written for every item size.
+const TOTAL_BYTES: usize = 64 * 1024 * 1024;
+const MAX_ITEMS: usize = 200_000;
+
+fn run(rt: &mut Runtime, item_size: usize, group_commit: Option<GroupCommit>) -> f64 {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut s, _r) = unbounded_file::<Vec<u8>>(dir.path().join("0")).unwrap();
+    if let Some(group_commit) = group_commit {
+        s = s.group_commit(group_commit);
+    }
+    let items = (TOTAL_BYTES / item_size).min(MAX_ITEMS);
+    let item = vec![7u8; item_size];
+
+    let started = Instant::now();
+    let stream = iter_ok::<_, Error>((0..items).map(move |_| item.clone()));
+    drop(rt.block_on(s.send_all(stream)).unwrap());
+    items as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let mut rt = Runtime::new().unwrap();
+    let configs: Vec<(&str, Option<GroupCommit>)> = vec![
+        ("default", None),
+        ("group 64KiB", Some(GroupCommit::new(64 * 1024))),
+        (
+            "group 1MiB 200us",
+            Some(GroupCommit::new(1024 * 1024).linger(Duration::from_micros(200))),
+        ),
+        (
+            "group 1MiB fsync",
+            Some(GroupCommit::new(1024 * 1024).fsync(true)),
+        ),
+    ];
+
+    println!("{:>10} {:>20} {:>15}", "item size", "config", "items/sec");
+    for &item_size in ITEM_SIZES {
+        for (name, group_commit) in &configs {
+            let items_per_sec = run(&mut rt, item_size, group_commit.clone());
+            println!("{:>10} {:>20} {:>15.0}", item_size, name, items_per_sec);
+        }
+    }
+}
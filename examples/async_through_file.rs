@@ -1,5 +1,4 @@
 use futures::stream::iter_ok;
-use pretty_env_logger;
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use tokio::prelude::*;
@@ -1,11 +1,10 @@
 use futures::stream::iter_ok;
 use futures::StartSend;
-use pretty_env_logger;
 use reqwest::r#async::{Client, Response};
 use std::time::Duration;
 use tokio::prelude::*;
 
-use futures_retry::{RetryPolicy, SinkRetryExt};
+use tokio_fs_stream::channel::{Backoff, RetryError, RetryPolicy};
 use tokio_fs_stream::SinkFsExt;
 
 // This is struct that implement Sink for ouer test.
@@ -13,7 +12,7 @@ struct PostSender {
     post_url: String,
     client: Client,
     sending_item: Option<String>,
-    sending_fut: Option<Box<Future<Item = Response, Error = reqwest::Error> + Send>>,
+    sending_fut: Option<Box<dyn Future<Item = Response, Error = reqwest::Error> + Send>>,
 }
 
 impl PostSender {
@@ -28,7 +27,7 @@ impl PostSender {
 
     fn try_get_fut(
         &mut self,
-    ) -> Option<&mut Box<Future<Item = Response, Error = reqwest::Error> + Send>> {
+    ) -> Option<&mut Box<dyn Future<Item = Response, Error = reqwest::Error> + Send>> {
         if self.sending_fut.is_none() {
             if let Some(ref item) = self.sending_item {
                 let fut = self.client.post(&self.post_url).body(item.clone()).send();
@@ -106,22 +105,24 @@ pub fn main() -> io::Result<()> {
     // know http.
     let sink = PostSender::new("http://httpbin.org/status/200,408,500,500,408".to_string());
 
-    // we wanna retry on some error. In case error is returned - item is saved on this as backup.
+    // we wanna retry on some error. Item that failed all attempts is saved in the dir as backup.
     use reqwest::StatusCode;
-    let error_handler = |e: reqwest::Error| -> RetryPolicy<reqwest::Error> {
-        if let Some(status) = e.status() {
-            return match status {
-                StatusCode::INTERNAL_SERVER_ERROR => RetryPolicy::WaitRetry(Duration::from_secs(5)),
-                StatusCode::REQUEST_TIMEOUT => RetryPolicy::Repeat,
-                _ => RetryPolicy::ForwardError(e),
-            };
-        }
-        RetryPolicy::ForwardError(e)
-    };
+    let backoff =
+        Backoff::exponential(Duration::from_millis(500), Duration::from_secs(5)).jitter(0.2);
+    let policy = RetryPolicy::new(backoff)
+        .max_attempts(3)
+        .retry_if(|e: &reqwest::Error| {
+            matches!(
+                e.status(),
+                Some(StatusCode::INTERNAL_SERVER_ERROR) | Some(StatusCode::REQUEST_TIMEOUT)
+            )
+        });
 
+    // save items in `dir_sender_test` when sink is not ready or item failed all attempts. Items
+    // rejected with other errors, e.g. 400, are kept in `dir_sender_test/dead_letter`.
     let write_stream_inside_sink = sink
-        .retry(error_handler) // repeat on some errors. When error occure and RetryPolicy::WaitRetry is used error is ignored and translated to Ok(AsyncSink::NotReady(item)).
-        .send_all_fs_backpresure(stream, "dir_sender_test".into())?; // save items in `dir_sender_test` when AsyncSink::NotReady(item) is returned.
+        .send_all_fs_retry(stream, "dir_sender_test".into(), policy)?
+        .dead_letters(RetryError::take_rejected)?;
 
     tokio::run(
         write_stream_inside_sink
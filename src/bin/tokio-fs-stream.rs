@@ -0,0 +1,148 @@
+//! Inspect dirs with items stored by `tokio-fs-stream`.
+//!
+//! Only `compact` and `purge` modify the dir.
+use serde_json::{json, Value};
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use tokio_fs_stream::channel::{compact, list_segments, purge, raw_records, verify_segment};
+
+const USAGE: &str = "Usage: tokio-fs-stream <command> <dir> [options]
+
+Commands:
+    list <dir>                  List files with number of items, size and sealed state
+    dump <dir> [--type <type>]  Print items as JSON lines. <type> is how items were
+                                serialized: raw (default, hex of bincode), string, bytes,
+                                u64, i64, f64 or json (string with JSON inside)
+    verify <dir>                Check framing of items and indexes of files
+    compact <dir> [--max-items <n>]
+                                Rewrite items into new files with up to <n> items
+                                (default 1000). Dir can't be used by other programs
+    purge <dir> [--all]         Remove sealed files, or all files with --all";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let (command, dir_path, options) = match args {
+        [command, dir_path, options @ ..] => (command.as_str(), PathBuf::from(dir_path), options),
+        _ => return Err(usage()),
+    };
+
+    match (command, options) {
+        ("list", []) => list(&dir_path),
+        ("dump", []) => dump(&dir_path, "raw"),
+        ("dump", [flag, item_type]) if flag == "--type" => dump(&dir_path, item_type),
+        ("verify", []) => verify(&dir_path),
+        ("compact", []) => rewrite(&dir_path, "1000"),
+        ("compact", [flag, max_items]) if flag == "--max-items" => rewrite(&dir_path, max_items),
+        ("purge", []) => remove(&dir_path, false),
+        ("purge", [flag]) if flag == "--all" => remove(&dir_path, true),
+        _ => Err(usage()),
+    }
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid arguments\n\n{}", USAGE),
+    )
+}
+
+fn list(dir_path: &Path) -> io::Result<()> {
+    println!("{:>8} {:>10} {:>12}  sealed", "file", "items", "bytes");
+    for segment in list_segments(dir_path)? {
+        println!(
+            "{:>8} {:>10} {:>12}  {}",
+            segment.index, segment.items, segment.bytes, segment.sealed
+        );
+    }
+    Ok(())
+}
+
+fn dump(dir_path: &Path, item_type: &str) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for segment in list_segments(dir_path)? {
+        for (ordinal, record) in raw_records(&segment.path)?.enumerate() {
+            let record = record?;
+            let line = json!({
+                "file": segment.index,
+                "ordinal": ordinal,
+                "offset": record.offset,
+                "written_at": record.written_at,
+                "item": decode(&record.payload, item_type)?,
+            });
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode(payload: &[u8], item_type: &str) -> io::Result<Value> {
+    fn invalid<E: std::fmt::Display>(err: E) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+
+    Ok(match item_type {
+        "raw" => Value::String(payload.iter().map(|byte| format!("{:02x}", byte)).collect()),
+        "string" => json!(bincode::deserialize::<String>(payload).map_err(invalid)?),
+        "bytes" => json!(bincode::deserialize::<Vec<u8>>(payload).map_err(invalid)?),
+        "u64" => json!(bincode::deserialize::<u64>(payload).map_err(invalid)?),
+        "i64" => json!(bincode::deserialize::<i64>(payload).map_err(invalid)?),
+        "f64" => json!(bincode::deserialize::<f64>(payload).map_err(invalid)?),
+        "json" => {
+            let text = bincode::deserialize::<String>(payload).map_err(invalid)?;
+            serde_json::from_str(&text).map_err(invalid)?
+        }
+        _ => return Err(usage()),
+    })
+}
+
+fn verify(dir_path: &Path) -> io::Result<()> {
+    let mut failed = false;
+    for segment in list_segments(dir_path)? {
+        let report = verify_segment(&segment.path)?;
+        if report.is_ok() {
+            println!("{}: ok, {} items", segment.index, report.items);
+        } else {
+            failed = true;
+            println!(
+                "{}: {} items, {} trailing bytes, {} bad index entries",
+                segment.index, report.items, report.trailing_bytes, report.bad_index_entries
+            );
+        }
+    }
+
+    if failed {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "verification failed",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn rewrite(dir_path: &Path, max_items: &str) -> io::Result<()> {
+    let max_items = max_items.parse().map_err(|_| usage())?;
+    let report = compact(dir_path, max_items, None)?;
+    println!(
+        "{} items moved from {} files to {} files",
+        report.items, report.files_removed, report.files_created
+    );
+    Ok(())
+}
+
+fn remove(dir_path: &Path, all: bool) -> io::Result<()> {
+    for path in purge(dir_path, all)? {
+        println!("removed {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use super::backend;
+use super::record;
+use super::segment;
+use futures::Poll;
+use log::debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Retention policy that moves fully read files to an archive dir instead of removing them.
+///
+/// Archived files are named `<archived at millis>-<file index>` and the oldest ones are removed
+/// when any of the limits is exceeded. By default archive is unbounded.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    dir_path: PathBuf,
+    max_files: Option<usize>,
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    compress: bool,
+}
+
+impl Archive {
+    /// Archive files in `dir_path`, e.g. `archive` dir inside the dir with files. Dir is created
+    /// if it doesn't exist.
+    pub fn new(dir_path: PathBuf) -> Self {
+        Archive {
+            dir_path,
+            max_files: None,
+            max_age: None,
+            max_bytes: None,
+            compress: false,
+        }
+    }
+
+    /// Keep at most `max_files` archived files.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Remove files archived more than `max_age` ago.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Keep at most `max_bytes` of archived files.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Compress archived files with gzip, they get `.gz` extension. Compression runs on the
+    /// blocking pool of the runtime, like moving files to the archive.
+    ///
+    /// # Errors
+    /// Returns `Unsupported` if crate is built without `compression` feature.
+    pub fn compress(mut self, compress: bool) -> io::Result<Self> {
+        if compress && !cfg!(feature = "compression") {
+            return Err(compression_disabled());
+        }
+        self.compress = compress;
+        Ok(self)
+    }
+
+    /// Dir where files are archived.
+    pub fn dir_path(&self) -> &Path {
+        &self.dir_path
+    }
+
+    /// Run `store` on the blocking pool, renaming or compressing a file doesn't block the task.
+    pub(crate) fn poll_store(&self, path: &Path) -> Poll<(), io::Error> {
+        backend::blocking(|| self.store(path))
+    }
+
+    /// Move file in `path` to the archive and remove archived files over the limits.
+    pub(crate) fn store(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.dir_path)?;
+        let name = format!(
+            "{}-{}",
+            record::now_millis(),
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+        );
+
+        if self.compress {
+            let archived = self.dir_path.join(format!("{}.gz", name));
+            debug!("Compressing {:?} to {:?}", path, archived);
+            compress_file(path, &archived)?;
+            fs::remove_file(path)?;
+        } else {
+            let archived = self.dir_path.join(name);
+            debug!("Archiving {:?} to {:?}", path, archived);
+            move_file(path, &archived)?;
+        }
+        segment::remove_index(path);
+        self.prune()
+    }
+
+    /// Remove the oldest archived files until archive fits into the limits.
+    fn prune(&self) -> io::Result<()> {
+        let mut archived = archived_files(&self.dir_path)?;
+        let mut total_bytes: u64 = archived.iter().map(|file| file.bytes).sum();
+        let now = record::now_millis();
+
+        for file in archived.drain(..) {
+            let over_count = self.max_files.is_some_and(|max| file.count_left > max);
+            let over_bytes = self.max_bytes.is_some_and(|max| total_bytes > max);
+            let too_old = self.max_age.is_some_and(|max_age| {
+                now.saturating_sub(file.archived_at) > max_age.as_millis() as u64
+            });
+            if !(over_count || over_bytes || too_old) {
+                break;
+            }
+            debug!("Removing archived file {:?}", file.path);
+            fs::remove_file(&file.path)?;
+            total_bytes -= file.bytes;
+        }
+        Ok(())
+    }
+}
+
+struct ArchivedFile {
+    path: PathBuf,
+    archived_at: u64,
+    bytes: u64,
+    // number of archived files including this one and newer ones.
+    count_left: usize,
+}
+
+/// Files in archive sorted from the oldest. Files with names not created by archive are ignored.
+fn archived_files(dir_path: &Path) -> io::Result<Vec<ArchivedFile>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let order = name
+            .to_str()
+            .map(|name| name.trim_end_matches(".gz"))
+            .and_then(|name| {
+                let mut parts = name.splitn(2, '-');
+                let archived_at = parts.next()?.parse::<u64>().ok()?;
+                let index = parts.next()?.parse::<usize>().ok()?;
+                Some((archived_at, index))
+            });
+        if let Some(order) = order {
+            files.push((order, entry.path(), entry.metadata()?.len()));
+        }
+    }
+    files.sort_unstable_by_key(|&(order, _, _)| order);
+
+    let count = files.len();
+    Ok(files
+        .into_iter()
+        .enumerate()
+        .map(|(i, ((archived_at, _index), path, bytes))| ArchivedFile {
+            path,
+            archived_at,
+            bytes,
+            count_left: count - i,
+        })
+        .collect())
+}
+
+/// Rename `path` to `archived`. When archive is on another file system the file is copied to a
+/// temporary file that is renamed after it's synced, so a partial copy is never archived.
+fn move_file(path: &Path, archived: &Path) -> io::Result<()> {
+    match fs::rename(path, archived) {
+        Err(ref err) if is_cross_device(err) => {
+            debug!("Copying {:?} to another file system", path);
+            let temp = segment::temp_path(archived);
+            fs::copy(path, &temp)?;
+            fs::File::open(&temp)?.sync_all()?;
+            fs::rename(&temp, archived)?;
+            fs::remove_file(path)
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn is_cross_device(err: &io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    err.raw_os_error() == Some(17)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_cross_device(_err: &io::Error) -> bool {
+    false
+}
+
+fn compression_disabled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compression is enabled only with `compression` feature",
+    )
+}
+
+#[cfg(feature = "compression")]
+fn compress_file(path: &Path, archived: &Path) -> io::Result<()> {
+    use flate2::{write::GzEncoder, Compression};
+
+    let mut input = fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(fs::File::create(archived)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress_file(_path: &Path, _archived: &Path) -> io::Result<()> {
+    Err(compression_disabled())
+}
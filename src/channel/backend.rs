@@ -0,0 +1,60 @@
+use futures::{try_ready, Async, Poll};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_fs::File;
+
+/// File opened by a [Backend](trait.Backend.html).
+pub trait BackendFile: AsyncRead + AsyncWrite + Send {
+    /// Ready with true if the file is sealed, so nothing will be appended to it.
+    fn poll_is_sealed(&mut self) -> Poll<bool, io::Error>;
+
+    fn poll_set_len(&mut self, len: u64) -> Poll<(), io::Error>;
+
+    fn poll_sync_data(&mut self) -> Poll<(), io::Error>;
+}
+
+/// How reads and writes of files in a dir are done, e.g. `DirSender::backend`.
+pub trait Backend: Send + Sync {
+    /// Read or append to `file`, it's opened for reading or appending and placed where reading
+    /// should start.
+    fn open(&self, file: std::fs::File) -> io::Result<Box<dyn BackendFile>>;
+}
+
+/// Default backend, every read and write is done on tokio blocking thread pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadPool;
+
+impl Backend for ThreadPool {
+    fn open(&self, file: std::fs::File) -> io::Result<Box<dyn BackendFile>> {
+        Ok(Box::new(File::from_std(file)))
+    }
+}
+
+impl BackendFile for File {
+    fn poll_is_sealed(&mut self) -> Poll<bool, io::Error> {
+        let metadata = try_ready!(self.poll_metadata());
+        Ok(metadata.permissions().readonly().into())
+    }
+
+    fn poll_set_len(&mut self, len: u64) -> Poll<(), io::Error> {
+        File::poll_set_len(self, len)
+    }
+
+    fn poll_sync_data(&mut self) -> Poll<(), io::Error> {
+        File::poll_sync_data(self)
+    }
+}
+
+/// Run blocking `f` on tokio thread pool, like reads and writes of `ThreadPool` are run.
+pub(crate) fn blocking<T, F>(f: F) -> Poll<T, io::Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    match tokio_threadpool::blocking(f) {
+        Ok(Async::Ready(result)) => result.map(Async::Ready),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(_) => Err(io::Error::other(
+            "`blocking` annotated I/O must be called from the context of the Tokio runtime.",
+        )),
+    }
+}
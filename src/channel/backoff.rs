@@ -0,0 +1,58 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Exponential backoff: delay doubles with every attempt until it reaches `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Backoff {
+    /// Start with `initial` delay, never wait longer than `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            jitter: 0.0,
+        }
+    }
+
+    /// Shorten every delay by a random part up to `fraction` of it, so many clients that failed
+    /// at the same time don't retry at the same time. `fraction` is clamped to `0.0..=1.0`.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before `attempt`, starting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max));
+        if self.jitter > 0.0 {
+            delay - delay.mul_f64(self.jitter * random_fraction(attempt))
+        } else {
+            delay
+        }
+    }
+}
+
+impl Default for Backoff {
+    /// From 100 milliseconds up to 30 seconds.
+    fn default() -> Self {
+        Backoff::exponential(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+/// Random number in `0.0..1.0`. Good enough for jitter, hasher keys are random for every call.
+fn random_fraction(attempt: u32) -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    attempt.hash(&mut hasher);
+    Instant::now().hash(&mut hasher);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
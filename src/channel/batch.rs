@@ -0,0 +1,294 @@
+use super::codec::DirReciver;
+use super::error::Error;
+use custom_error::{add_type_bounds, custom_error};
+use futures::prelude::*;
+use futures::try_ready;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Limits of a batch of items, see `DirReciver::batches` and `SinkFsExt::send_all_fs_batched`.
+///
+/// Batch is complete when it has `max_items` items or `max_bytes` serialized bytes. Incomplete
+/// batch is sent when no more items are ready and `linger` passed since its first item.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    max_items: usize,
+    max_bytes: Option<u64>,
+    linger: Duration,
+}
+
+impl Batch {
+    /// Batches of up to `max_items` items, incomplete batch is sent as soon as no more items are
+    /// ready.
+    pub fn new(max_items: usize) -> Self {
+        Batch {
+            max_items: max_items.max(1),
+            max_bytes: None,
+            linger: Duration::from_secs(0),
+        }
+    }
+
+    /// Complete the batch when its items take at least `max_bytes` serialized.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Wait up to `linger` for more items before sending incomplete batch.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = linger;
+        self
+    }
+}
+
+/// Collects items into a batch.
+struct Batcher<T> {
+    config: Batch,
+    items: Vec<T>,
+    bytes: u64,
+    // started with the first item of the batch.
+    linger: Option<Delay>,
+}
+
+impl<T> Batcher<T> {
+    fn new(config: Batch) -> Self {
+        Batcher {
+            items: Vec::with_capacity(config.max_items),
+            config,
+            bytes: 0,
+            linger: None,
+        }
+    }
+
+    /// Serialized size of `item`, it's computed only if batches are limited by bytes.
+    fn size_of(&self, item: &T) -> Result<u64, Error>
+    where
+        T: Serialize,
+    {
+        match self.config.max_bytes {
+            Some(_) => bincode::serialized_size(item).map_err(Error::encode),
+            None => Ok(0),
+        }
+    }
+
+    /// Add item that takes `size` bytes serialized.
+    fn push(&mut self, item: T, size: u64) {
+        self.bytes += size;
+        if self.items.is_empty() && self.config.linger > Duration::from_secs(0) {
+            self.linger = Some(Delay::new(Instant::now() + self.config.linger));
+        }
+        self.items.push(item);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.items.len() >= self.config.max_items
+            || self.config.max_bytes.is_some_and(|max| self.bytes >= max)
+    }
+
+    /// Ready when incomplete batch waited long enough for more items.
+    fn poll_linger(&mut self) -> Async<()> {
+        if let Some(delay) = self.linger.as_mut() {
+            match delay.poll() {
+                Ok(Async::NotReady) => return Async::NotReady,
+                Ok(Async::Ready(())) => (),
+                Err(err) => warn!("Timer failed: {}, sending incomplete batch", err),
+            }
+        }
+        Async::Ready(())
+    }
+
+    fn take(&mut self) -> Vec<T> {
+        self.bytes = 0;
+        self.linger = None;
+        std::mem::replace(&mut self.items, Vec::with_capacity(self.config.max_items))
+    }
+
+    /// Put back batch the sink didn't accept.
+    fn restore(&mut self, items: Vec<T>) {
+        self.items = items;
+    }
+}
+
+/// Stream of batches of items read from a dir, see `DirReciver::batches`.
+pub struct Batches<T> {
+    dir_reciver: DirReciver<T>,
+    batcher: Batcher<T>,
+    done: bool,
+}
+
+pub fn new_batches<T>(dir_reciver: DirReciver<T>, batch: Batch) -> Batches<T> {
+    Batches {
+        dir_reciver,
+        batcher: Batcher::new(batch),
+        done: false,
+    }
+}
+
+impl<T> Batches<T> {
+    pub fn get_ref(&self) -> &DirReciver<T> {
+        &self.dir_reciver
+    }
+
+    pub fn get_mut(&mut self) -> &mut DirReciver<T> {
+        &mut self.dir_reciver
+    }
+}
+
+/// Size of items is known from the dir, they aren't serialized again.
+impl<T> Stream for Batches<T>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    type Item = Vec<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done && !self.batcher.is_full() {
+            match self.dir_reciver.poll()? {
+                Async::Ready(Some(item)) => {
+                    let size = self.dir_reciver.item_size();
+                    self.batcher.push(item, size);
+                }
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => {
+                    if self.batcher.is_empty() || self.batcher.poll_linger().is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                    break;
+                }
+            }
+        }
+
+        if self.batcher.is_empty() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(self.batcher.take())))
+        }
+    }
+}
+
+custom_error! {
+    /// Error of [BatchSink](struct.BatchSink.html).
+    pub BatchError<E>
+    /// Item can't be serialized to get its size.
+    Encode { error: Error } = @{ format!("Can't add item to batch: {}", error) },
+    /// The sink of batches failed.
+    Sink { error: E } = "Sink error",
+}
+
+impl<E> From<E> for BatchError<E> {
+    fn from(error: E) -> Self {
+        BatchError::Sink { error }
+    }
+}
+
+/// Sink of items that sends them to a sink of batches, see `SinkFsExt::send_all_fs_batched`.
+///
+/// `poll_complete` sends incomplete batch only after linger passed, `close` sends it immediately.
+pub struct BatchSink<S, T> {
+    sink: S,
+    batcher: Batcher<T>,
+}
+
+pub fn new_batch_sink<S, T: Serialize>(sink: S, batch: Batch) -> BatchSink<S, T> {
+    BatchSink {
+        sink,
+        batcher: Batcher::new(batch),
+    }
+}
+
+impl<S, T> BatchSink<S, T> {
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Consume adapter and return the sink. Items of incomplete batch are lost, see
+    /// `take_pending`.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S, T> BatchSink<S, T>
+where
+    S: Sink<SinkItem = Vec<T>>,
+    T: Serialize,
+{
+    /// Take items of incomplete batch. `SendAllUnorderedFs` writes them to the dir on shutdown.
+    pub fn take_pending(&mut self) -> Vec<T> {
+        self.batcher.take()
+    }
+
+    /// Like `start_send` but `size` of the serialized item is known, e.g. for items read from the
+    /// dir, see `DirReciver::item_size`.
+    pub fn start_send_sized(
+        &mut self,
+        item: T,
+        size: u64,
+    ) -> StartSend<T, BatchError<S::SinkError>> {
+        if self.batcher.is_full() && self.flush_batch()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.batcher.push(item, size);
+        if self.batcher.is_full() {
+            self.flush_batch()?;
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    /// Send collected items to the sink.
+    fn flush_batch(&mut self) -> Poll<(), BatchError<S::SinkError>> {
+        if self.batcher.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        match self.sink.start_send(self.batcher.take())? {
+            AsyncSink::Ready => Ok(Async::Ready(())),
+            AsyncSink::NotReady(items) => {
+                self.batcher.restore(items);
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<S, T> Sink for BatchSink<S, T>
+where
+    S: Sink<SinkItem = Vec<T>>,
+    T: Serialize,
+{
+    type SinkItem = T;
+    type SinkError = BatchError<S::SinkError>;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let size = self
+            .batcher
+            .size_of(&item)
+            .map_err(|error| BatchError::Encode { error })?;
+        self.start_send_sized(item, size)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let flushed = if self.batcher.is_full() || self.batcher.poll_linger().is_ready() {
+            self.flush_batch()?
+        } else {
+            Async::NotReady
+        };
+        try_ready!(self.sink.poll_complete());
+        Ok(flushed)
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.flush_batch());
+        self.sink.close().map_err(BatchError::from)
+    }
+}
@@ -0,0 +1,95 @@
+use futures::{try_ready, Async, Future, Poll};
+use log::{debug, warn};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Stop offering items to a sink that keeps failing.
+///
+/// After `threshold` `NotReady` or errors in a row the breaker opens and items from the stream are
+/// written straight to the dir. After `cooldown` one item read from the dir is sent as a probe,
+/// the breaker closes if the sink accepts it and opens again otherwise.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Open after `threshold` failures in a row, probe the sink every `cooldown`.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+        }
+    }
+}
+
+enum State {
+    Closed,
+    // waiting for the cooldown.
+    Open(Delay),
+    // cooldown passed, next item is a probe.
+    HalfOpen,
+}
+
+pub struct Breaker {
+    config: CircuitBreaker,
+    // failures in a row.
+    failures: u32,
+    state: State,
+}
+
+impl Breaker {
+    pub fn new(config: CircuitBreaker) -> Self {
+        Breaker {
+            config,
+            failures: 0,
+            state: State::Closed,
+        }
+    }
+
+    /// Return true if items from the stream can be sent to the sink.
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Return true if waiting for the cooldown.
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, State::Open(_))
+    }
+
+    /// Ready when items read from the dir can be sent to the sink.
+    pub fn poll_probe(&mut self) -> Poll<(), tokio::timer::Error> {
+        if let State::Open(ref mut delay) = self.state {
+            try_ready!(delay.poll());
+            debug!("Circuit breaker is half open, probing the sink");
+            self.state = State::HalfOpen;
+        }
+        Ok(Async::Ready(()))
+    }
+
+    pub fn success(&mut self) {
+        self.failures = 0;
+        if let State::HalfOpen = self.state {
+            debug!("Circuit breaker is closed");
+            self.state = State::Closed;
+        }
+    }
+
+    /// Register failure. Call `poll_probe` after that, so the task is woken after the cooldown.
+    pub fn failure(&mut self) {
+        self.failures += 1;
+        let open = match self.state {
+            State::Closed => self.failures >= self.config.threshold,
+            State::HalfOpen => true,
+            State::Open(_) => false,
+        };
+        if open {
+            warn!(
+                "Circuit breaker is open after {} failures, probing the sink in {:?}",
+                self.failures, self.config.cooldown
+            );
+            self.state = State::Open(Delay::new(Instant::now() + self.config.cooldown));
+        }
+    }
+}
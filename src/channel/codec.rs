@@ -0,0 +1,312 @@
+//! Typed channels: items `T` serialized with bincode on top of raw channels.
+
+use super::archive::Archive;
+use super::backend::Backend;
+use super::batch::{new_batches, Batch, Batches};
+use super::error::Error;
+use super::fs_receiver::{new_raw, new_raw_dir_reciver, RawDirReciver, RawFileReciver};
+use super::fs_sender::{new_raw_dir_sender, unbounded_raw, RawDirSender, RawFileSender};
+use super::group_commit::GroupCommit;
+use super::store::SegmentStore;
+use futures::prelude::*;
+use futures::try_ready;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn encode<T: Serialize>(item: &T) -> Result<Vec<u8>, Error> {
+    bincode::serialize(item).map_err(Error::encode)
+}
+
+/// Unbounded Sender through file that will be saving all item until fs limit.
+pub struct UnboundedFileSender<T> {
+    raw: RawFileSender,
+    _item: PhantomData<fn(T)>,
+}
+
+/// Create new unbounded sender file in path, see `unbounded_raw`.
+pub fn unbounded<T>(path: &Path) -> io::Result<UnboundedFileSender<T>> {
+    Ok(UnboundedFileSender {
+        raw: unbounded_raw(path)?,
+        _item: PhantomData,
+    })
+}
+
+impl<T> UnboundedFileSender<T> {
+    /// Number of items stored in the file, including items that were there before.
+    pub fn items(&self) -> u64 {
+        self.raw.items()
+    }
+
+    /// Collect items and write them together, see `GroupCommit`.
+    pub fn group_commit(self, group_commit: GroupCommit) -> Self {
+        UnboundedFileSender {
+            raw: self.raw.group_commit(group_commit),
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T> Sink for UnboundedFileSender<T>
+where
+    T: Serialize,
+{
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.raw.poll_ready()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.raw.push(&encode(&item)?)?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.raw.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.raw.close()
+    }
+}
+
+/// Sender of items to a dir. It's creating next file after last one is full.
+pub struct DirSender<T> {
+    raw: RawDirSender,
+    _item: PhantomData<fn(T)>,
+}
+
+pub fn new_dir_sender<T>(
+    store: Arc<dyn SegmentStore>,
+    max_number_of_items: usize,
+) -> io::Result<DirSender<T>> {
+    Ok(DirSender {
+        raw: new_raw_dir_sender(store, max_number_of_items)?,
+        _item: PhantomData,
+    })
+}
+
+impl<T> DirSender<T> {
+    /// Path of the dir, `None` if segments are not stored on local fs.
+    pub fn dir_path(&self) -> Option<&Path> {
+        self.raw.dir_path()
+    }
+
+    /// Read and write files with `backend`, see `RawDirSender::backend`.
+    pub fn backend(self, backend: Arc<dyn Backend>) -> io::Result<Self> {
+        Ok(DirSender {
+            raw: self.raw.backend(backend)?,
+            _item: PhantomData,
+        })
+    }
+
+    /// Reserve `len` bytes on disk for every file, see `RawDirSender::preallocate`.
+    pub fn preallocate(self, len: u64) -> io::Result<Self> {
+        Ok(DirSender {
+            raw: self.raw.preallocate(len)?,
+            _item: PhantomData,
+        })
+    }
+
+    /// Collect items and write them together, see `GroupCommit`.
+    pub fn group_commit(self, group_commit: GroupCommit) -> Self {
+        DirSender {
+            raw: self.raw.group_commit(group_commit),
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T> Sink for DirSender<T>
+where
+    T: Serialize,
+{
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.raw.poll_ready()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.raw.push(&encode(&item)?)?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.raw.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.raw.close()
+    }
+}
+
+/// Stream thats read items from file with monitoring changes.
+pub struct FileReciver<T> {
+    raw: RawFileReciver,
+    _item: PhantomData<fn() -> T>,
+}
+
+pub fn new_file_reciver<T>(path: PathBuf) -> io::Result<FileReciver<T>> {
+    Ok(FileReciver {
+        raw: new_raw(path)?,
+        _item: PhantomData,
+    })
+}
+
+impl<T> FileReciver<T> {
+    /// Ordinal of the next item read from the file. Expired items are counted too.
+    pub fn position(&self) -> u64 {
+        self.raw.position()
+    }
+
+    /// Move to item `n` in the file without decoding items before it. Returns ordinal of the item
+    /// reciver moved to, which is less than `n` if file has less items.
+    pub fn seek_to_item(&mut self, n: u64) -> io::Result<u64> {
+        self.raw.seek_to_item(n)
+    }
+
+    /// Skip items older than `ttl`.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.raw.set_ttl(ttl);
+    }
+
+    /// Don't remove the file when it is sealed and fully read.
+    pub fn set_keep_file(&mut self, keep_file: bool) {
+        self.raw.set_keep_file(keep_file);
+    }
+
+    /// Move the file to `archive` instead of removing it when it is sealed and fully read.
+    pub fn set_archive(&mut self, archive: Archive) {
+        self.raw.set_archive(archive);
+    }
+
+    /// Number of items skipped because they were older than ttl.
+    pub fn expired_items(&self) -> u64 {
+        self.raw.expired_items()
+    }
+
+    /// Return true if all items written to the file so far are read and reciver waits for more.
+    pub fn is_caught_up(&self) -> bool {
+        self.raw.is_caught_up()
+    }
+}
+
+impl<T> Stream for FileReciver<T>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.raw.poll()) {
+            Some(payload) => bincode::deserialize(&payload)
+                .map(|item| Async::Ready(Some(item)))
+                .map_err(|err| {
+                    Error::decode(err, self.raw.path().to_path_buf(), self.raw.item_offset())
+                }),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+/// Stream to read serialized items `T` from dir.
+pub struct DirReciver<T> {
+    raw: RawDirReciver,
+    _item: PhantomData<fn() -> T>,
+}
+
+pub fn new_dir_reciver<T>(store: Arc<dyn SegmentStore>) -> io::Result<DirReciver<T>> {
+    Ok(DirReciver {
+        raw: new_raw_dir_reciver(store)?,
+        _item: PhantomData,
+    })
+}
+
+impl<T> DirReciver<T> {
+    /// Read files with `backend` instead of `ThreadPool`.
+    pub fn set_backend(&mut self, backend: Arc<dyn Backend>) -> io::Result<()> {
+        self.raw.set_backend(backend)
+    }
+
+    /// Skip items older than `ttl`. Files with all items expired are removed without reading
+    /// them.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.raw.set_ttl(ttl);
+    }
+
+    /// Don't remove or modify anything in the dir. Read files and expired files are only skipped,
+    /// so the dir can be read again later.
+    pub fn set_keep_files(&mut self, keep_files: bool) {
+        self.raw.set_keep_files(keep_files);
+    }
+
+    /// Move fully read, expired and skipped files to `archive` instead of removing them, see
+    /// `RawDirReciver::set_archive`.
+    pub fn set_archive(&mut self, archive: Archive) -> io::Result<()> {
+        self.raw.set_archive(archive)
+    }
+
+    /// Number of items skipped because they were older than ttl. Items from removed expired files
+    /// are not counted, see `expired_files`.
+    pub fn expired_items(&self) -> u64 {
+        self.raw.expired_items()
+    }
+
+    /// Number of files removed (or skipped if files are kept) without reading because all items
+    /// inside were expired.
+    pub fn expired_files(&self) -> u64 {
+        self.raw.expired_files()
+    }
+
+    /// Return true if all items written to the dir so far are read and reciver waits for more.
+    pub fn is_caught_up(&self) -> bool {
+        self.raw.is_caught_up()
+    }
+
+    /// Size of the last item returned by the stream, when it was serialized.
+    pub fn item_size(&self) -> u64 {
+        self.raw.item_size()
+    }
+
+    /// Skip `n` items without decoding them, see `RawDirReciver::skip_items`.
+    pub fn skip_items(&mut self, n: u64) -> Result<u64, Error> {
+        self.raw.skip_items(n)
+    }
+
+    /// Move the current file to `quarantine` dir inside the dir and continue with the next file,
+    /// see `RawDirReciver::quarantine_file`.
+    pub fn quarantine_file(&mut self) -> Result<bool, Error> {
+        self.raw.quarantine_file()
+    }
+
+    /// Read items in batches limited by `batch`, e.g. to send them with a bulk API.
+    pub fn batches(self, batch: Batch) -> Batches<T> {
+        new_batches(self, batch)
+    }
+}
+
+impl<T> Stream for DirReciver<T>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.raw.poll()) {
+            Some(payload) => bincode::deserialize(&payload)
+                .map(|item| Async::Ready(Some(item)))
+                .map_err(|err| {
+                    let (path, offset) = self.raw.item_location();
+                    Error::decode(err, path.to_path_buf(), offset)
+                }),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
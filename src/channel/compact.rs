@@ -0,0 +1,88 @@
+use super::inspect::raw_records;
+use super::lock::DirLock;
+use super::record;
+use super::segment::{self, SegmentWriter};
+use log::debug;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Result of [compact](fn.compact.html).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Number of files replaced.
+    pub files_removed: usize,
+    /// Number of new files.
+    pub files_created: usize,
+    /// Number of items moved to new files.
+    pub items: u64,
+    /// Number of items dropped because they were older than ttl.
+    pub expired_items: u64,
+}
+
+/// Rewrite items stored in `dir_path` into new sealed files with up to `max_items_in_file` items.
+/// Items older than `ttl` and incomplete items left by a crash are dropped.
+///
+/// Every new file is written to a temporary file and renamed, then old files are removed. New
+/// files get indexes after the old ones, so a crash in the middle can duplicate items but never
+/// lose them.
+///
+/// Recivers start from the first new file, a sender continues with a file after the last one.
+///
+/// # Errors
+/// Returns `WouldBlock` if a sender or reciver uses the dir.
+pub fn compact(
+    dir_path: &Path,
+    max_items_in_file: usize,
+    ttl: Option<Duration>,
+) -> io::Result<CompactReport> {
+    let _lock = DirLock::exclusive(dir_path)?;
+    segment::remove_temp_files(dir_path)?;
+
+    let max_items_in_file = if max_items_in_file == 0 {
+        u64::MAX
+    } else {
+        max_items_in_file as u64
+    };
+    let old_indexes = segment::file_indexes(dir_path)?;
+    let mut next_index = old_indexes.last().map_or(0, |last| last + 1);
+    let mut report = CompactReport::default();
+    let mut writer: Option<SegmentWriter> = None;
+
+    for &index in &old_indexes {
+        for record in raw_records(&segment::file_path(dir_path, index))? {
+            let record = record?;
+            if ttl.is_some_and(|ttl| record::is_expired(record.written_at, ttl)) {
+                report.expired_items += 1;
+                continue;
+            }
+
+            if writer
+                .as_ref()
+                .is_some_and(|w| w.items == max_items_in_file)
+            {
+                writer.take().unwrap().finish()?;
+            }
+            if writer.is_none() {
+                writer = Some(SegmentWriter::new(dir_path, next_index)?);
+                next_index += 1;
+                report.files_created += 1;
+            }
+            writer
+                .as_mut()
+                .unwrap()
+                .write(record.written_at, &record.payload)?;
+            report.items += 1;
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+
+    for &index in &old_indexes {
+        segment::remove(&segment::file_path(dir_path, index))?;
+        report.files_removed += 1;
+    }
+    debug!("Compacted {:?}: {:?}", dir_path, report);
+    Ok(report)
+}
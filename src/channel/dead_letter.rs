@@ -0,0 +1,163 @@
+use super::codec::{new_dir_sender, DirSender};
+use super::error::Error;
+use super::inspect::raw_records;
+use super::lock::DirLock;
+use super::record;
+use super::segment::{self, SegmentWriter};
+use super::store::LocalFs;
+use futures::prelude::*;
+use futures::try_ready;
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Dir inside the dir with files where items rejected by the sink are stored.
+pub const DEAD_LETTER_DIR: &str = "dead_letter";
+
+/// Item the sink rejected with an error that can't be retried.
+///
+/// Read them with `inspect_dir_fs::<DeadLetter<T>>(dir_path.join(DEAD_LETTER_DIR))`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter<T> {
+    pub item: T,
+    /// Message of the error returned by the sink.
+    pub error: String,
+    /// Time when item was rejected, in milliseconds since UNIX epoch.
+    pub rejected_at: u64,
+}
+
+/// Writes dead letters of `SendAllUnorderedFs`.
+pub struct DeadLetters<T> {
+    sender: DirSender<DeadLetter<T>>,
+    // dead letters the sender didn't accept yet.
+    pending: VecDeque<DeadLetter<T>>,
+}
+
+impl<T> DeadLetters<T>
+where
+    T: Serialize,
+{
+    /// Store dead letters in `DEAD_LETTER_DIR` inside `dir_path`.
+    pub fn new(dir_path: &Path) -> io::Result<Self> {
+        let dead_letter_dir = dir_path.join(DEAD_LETTER_DIR);
+        std::fs::create_dir_all(&dead_letter_dir)?;
+        Ok(DeadLetters {
+            sender: new_dir_sender(Arc::new(LocalFs::new(dead_letter_dir)?), 1000)?,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn push(&mut self, item: T, error: String) {
+        self.pending.push_back(DeadLetter {
+            item,
+            error,
+            rejected_at: record::now_millis(),
+        });
+    }
+
+    pub fn poll_complete(&mut self) -> Poll<(), Error> {
+        while let Some(dead_letter) = self.pending.pop_front() {
+            if let AsyncSink::NotReady(dead_letter) = self.sender.start_send(dead_letter)? {
+                self.pending.push_front(dead_letter);
+                try_ready!(self.sender.poll_complete());
+            }
+        }
+        self.sender.poll_complete()
+    }
+
+    pub fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        self.sender.close()
+    }
+}
+
+/// Move items rejected by the sink from `DEAD_LETTER_DIR` back to `dir_path`, so they are sent
+/// again on the next start. Returns number of moved items.
+///
+/// Items are written to new sealed files with up to `max_items_in_file` items, then dead letters
+/// are removed. A crash in the middle can duplicate items but never lose them. The last file of
+/// the dir is sealed first if the sender was killed before it closed it.
+///
+/// # Errors
+/// Returns `WouldBlock` if a sender or reciver uses the dir.
+pub fn reinject_dead_letters<T>(dir_path: &Path, max_items_in_file: usize) -> io::Result<u64>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let dead_letter_dir = dir_path.join(DEAD_LETTER_DIR);
+    if !dead_letter_dir.is_dir() {
+        return Ok(0);
+    }
+    let _lock = DirLock::exclusive(dir_path)?;
+    let _dead_letter_lock = DirLock::exclusive(&dead_letter_dir)?;
+    segment::remove_temp_files(dir_path)?;
+
+    let max_items_in_file = if max_items_in_file == 0 {
+        u64::MAX
+    } else {
+        max_items_in_file as u64
+    };
+    let dead_indexes = segment::file_indexes(&dead_letter_dir)?;
+    let last_index = segment::file_indexes(dir_path)?.last().cloned();
+    if let Some(last) = last_index {
+        // a reader waits for more items in an unsealed file, it would never reach the new files.
+        let path = segment::file_path(dir_path, last);
+        if !segment::is_sealed(&path)? {
+            segment::recover(&path, true)?;
+            segment::seal(&path)?;
+        }
+    }
+    let mut next_index = last_index.map_or(0, |last| last + 1);
+    let mut writer: Option<SegmentWriter> = None;
+    let mut items = 0;
+
+    for &index in &dead_indexes {
+        let path = segment::file_path(&dead_letter_dir, index);
+        for record in raw_records(&path)? {
+            let record = record?;
+            let dead_letter: DeadLetter<T> = bincode::deserialize(&record.payload)
+                .map_err(|err| invalid_dead_letter(&path, record.offset, err))?;
+            let payload = bincode::serialize(&dead_letter.item)
+                .map_err(|err| invalid_dead_letter(&path, record.offset, err))?;
+
+            if writer
+                .as_ref()
+                .is_some_and(|w| w.items == max_items_in_file)
+            {
+                writer.take().unwrap().finish()?;
+            }
+            if writer.is_none() {
+                writer = Some(SegmentWriter::new(dir_path, next_index)?);
+                next_index += 1;
+            }
+            writer
+                .as_mut()
+                .unwrap()
+                .write(record::now_millis(), &payload)?;
+            items += 1;
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+
+    for &index in &dead_indexes {
+        segment::remove(&segment::file_path(&dead_letter_dir, index))?;
+    }
+    debug!("Reinjected {} dead letters to {:?}", items, dir_path);
+    Ok(items)
+}
+
+fn invalid_dead_letter(path: &Path, offset: u64, err: bincode::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Can't decode dead letter at offset {} in {:?}: {}",
+            offset, path, err
+        ),
+    )
+}
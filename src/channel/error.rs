@@ -1,17 +1,94 @@
-use bincode::Error as AsyncBinCodeError;
 use custom_error::custom_error;
-use notify::Error as NotifyError;
+use std::io;
+use std::path::{Path, PathBuf};
 
-custom_error! { pub Error
-    AsyncBinCode { source: AsyncBinCodeError } = "Async bin code error {source}",
-    NotifyError { source: NotifyError } = "Notify error {source}",
+custom_error! {
+    /// Error of senders and recivers storing items on disk.
+    pub Error
+    /// Operation on file system failed, e.g. disk is full.
+    Io { source: io::Error } = "I/O error: {source}",
+    /// Item stored in a file can't be deserialized.
+    Decode { source: bincode::Error, path: PathBuf, offset: u64 } =
+        @{ format!("Can't decode item at offset {} in {:?}: {}", offset, path, source) },
+    /// Item can't be serialized.
+    Encode { source: bincode::Error } = "Can't encode item: {source}",
+    /// Watching file for changes failed.
+    Watcher { source: notify::Error } = "Watcher error: {source}",
+    /// Timer used to delay items failed.
+    Timer { source: tokio::timer::Error } = "Timer error: {source}",
+    /// Files in dir are not what sender or reciver expects.
+    Layout { path: PathBuf, reason: String } = @{ format!("Invalid layout of {:?}: {}", path, reason) },
+    /// Lane can't store more items.
+    Quota { path: PathBuf, max_items: usize } =
+        @{ format!("Quota of {} items in {:?} is exhausted", max_items, path) },
 }
 
-impl From<std::io::Error> for Error {
-    #[inline]
-    fn from(oth: std::io::Error) -> Self {
-        Error::AsyncBinCode {
-            source: AsyncBinCodeError::from(oth),
+impl Error {
+    /// Kind of I/O error, `None` if it's not an I/O error.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Error::Io { source } => Some(source.kind()),
+            _ => None,
+        }
+    }
+
+    /// Error from serializing item. I/O errors reported by bincode are kept as I/O errors.
+    pub(crate) fn encode(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(source) => Error::Io { source },
+            _ => Error::Encode { source: err },
+        }
+    }
+
+    /// `Error::Layout` inside I/O error with `InvalidData` kind, for functions returning
+    /// `io::Result`. Recivers and senders return it unwrapped, see `Error::from_io`.
+    pub(crate) fn layout_io(path: &Path, reason: String) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            Error::Layout {
+                path: path.to_path_buf(),
+                reason,
+            },
+        )
+    }
+
+    /// `Error::Watcher` inside I/O error, for the stream returned by `SegmentStore::watch`.
+    /// Recivers return it unwrapped, see `Error::from_io`.
+    pub(crate) fn watcher_io(err: notify::Error) -> io::Error {
+        let kind = match err {
+            notify::Error::Io(ref err) => err.kind(),
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, Error::Watcher { source: err })
+    }
+
+    /// Error of I/O operation, `Error::Layout` or `Error::Watcher` inside it is returned unwrapped.
+    pub(crate) fn from_io(err: io::Error) -> Self {
+        if !err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return Error::Io { source: err };
+        }
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<Error>()) {
+            Some(Ok(err)) => *err,
+            Some(Err(inner)) => Error::Io {
+                source: io::Error::new(kind, inner),
+            },
+            None => Error::Io {
+                source: kind.into(),
+            },
+        }
+    }
+
+    /// Error from deserializing item at `offset` in file in `path`. I/O errors reported by bincode
+    /// are kept as I/O errors.
+    pub(crate) fn decode(err: bincode::Error, path: PathBuf, offset: u64) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(source) => Error::Io { source },
+            _ => Error::Decode {
+                source: err,
+                path,
+                offset,
+            },
         }
     }
 }
@@ -0,0 +1,191 @@
+use super::error::Error;
+use super::segment::SIZE_PREFIX;
+use bytes::{Bytes, BytesMut};
+use futures::{try_ready, Async, Poll};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Size of the time when item was written, stored before the payload.
+pub const WRITTEN_AT: usize = 8;
+
+/// Maximal size of a frame. Bigger items are rejected by writers, bigger size read from a file
+/// means the file is broken.
+pub const MAX_FRAME: usize = 1 << 30;
+
+const READ_SIZE: usize = 8 * 1024;
+
+// Buffer grows by at most this much at once, so a broken size doesn't allocate before the data is
+// there.
+const MAX_RESERVE: usize = 1024 * 1024;
+
+/// Size of the frame of `payload`, see `FrameWriter::push`.
+///
+/// # Errors
+/// Returns `InvalidInput` if the frame would be bigger than `MAX_FRAME`.
+pub fn frame_size(payload: &[u8]) -> io::Result<u32> {
+    let size = WRITTEN_AT + payload.len();
+    if size > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Item of {} bytes is too big", payload.len()),
+        ));
+    }
+    Ok(size as u32)
+}
+
+/// Reads frames: size of the frame as u32 big endian followed by the frame.
+pub struct FrameReader<R> {
+    reader: R,
+    path: PathBuf,
+    buffer: BytesMut,
+    // offset of the first byte in the buffer.
+    offset: u64,
+    // offset of the end of the file if it's known, frames are never longer than the rest of it.
+    end: Option<u64>,
+}
+
+impl<R> FrameReader<R> {
+    /// Read frames from `reader` placed at `offset` in the file in `path`.
+    pub fn new(reader: R, path: &Path, offset: u64) -> Self {
+        FrameReader {
+            reader,
+            path: path.to_path_buf(),
+            buffer: BytesMut::with_capacity(READ_SIZE),
+            offset,
+            end: None,
+        }
+    }
+
+    /// File doesn't grow beyond `end`, frame that doesn't fit is incomplete and it isn't read.
+    pub fn with_end(mut self, end: u64) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Offset of the next frame in the file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<R: AsyncRead> FrameReader<R> {
+    /// Read the next frame without its size. Returns `None` at the end of the file, incomplete
+    /// frame at the end is returned when the rest of it is appended.
+    ///
+    /// # Errors
+    /// Returns `InvalidData` with `Error::Layout` inside if size of the frame is bigger than
+    /// `MAX_FRAME`.
+    pub fn poll_frame(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        loop {
+            let prefix = SIZE_PREFIX as usize;
+            let needed = if self.buffer.len() >= prefix {
+                let mut size = [0u8; SIZE_PREFIX as usize];
+                size.copy_from_slice(&self.buffer[..prefix]);
+                let size = u32::from_be_bytes(size) as usize;
+                if size > MAX_FRAME {
+                    return Err(Error::layout_io(
+                        &self.path,
+                        format!(
+                            "item at offset {} has {} bytes, more than {}",
+                            self.offset, size, MAX_FRAME
+                        ),
+                    ));
+                }
+                if self
+                    .end
+                    .is_some_and(|end| self.offset + (prefix + size) as u64 > end)
+                {
+                    return Ok(Async::Ready(None));
+                }
+                if self.buffer.len() >= prefix + size {
+                    self.buffer.advance(prefix);
+                    self.offset += (prefix + size) as u64;
+                    return Ok(Async::Ready(Some(self.buffer.split_to(size).freeze())));
+                }
+                prefix + size - self.buffer.len()
+            } else {
+                prefix - self.buffer.len()
+            };
+
+            self.buffer.reserve(needed.clamp(READ_SIZE, MAX_RESERVE));
+            if try_ready!(AsyncRead::read_buf(&mut self.reader, &mut self.buffer)) == 0 {
+                return Ok(Async::Ready(None));
+            }
+        }
+    }
+}
+
+/// Writes frames read by `FrameReader`. Every frame is an item: time when it was written as u64
+/// little endian and the payload.
+pub struct FrameWriter<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    // bytes of the buffer already written.
+    written: usize,
+}
+
+impl<W> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        FrameWriter {
+            writer,
+            buffer: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Bytes waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.written
+    }
+
+    /// Add item to the buffer. Returns size of the frame with its size prefix.
+    pub fn push(&mut self, written_at: u64, payload: &[u8]) -> io::Result<u64> {
+        let size = frame_size(payload)?;
+        self.buffer.extend_from_slice(&size.to_be_bytes());
+        self.buffer.extend_from_slice(&written_at.to_le_bytes());
+        self.buffer.extend_from_slice(payload);
+        Ok(SIZE_PREFIX + u64::from(size))
+    }
+}
+
+impl<W: AsyncWrite> FrameWriter<W> {
+    /// Write buffered frames and flush the writer.
+    pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        while self.written < self.buffer.len() {
+            let n = try_ready!(self.writer.poll_write(&self.buffer[self.written..]));
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.written += n;
+        }
+        self.buffer.clear();
+        self.written = 0;
+        self.writer.poll_flush()
+    }
+
+    pub fn poll_close(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_flush());
+        self.writer.shutdown()
+    }
+}
+
+/// Split frame to time when item was written and its payload. Returns `None` if the frame is too
+/// short.
+pub fn split_frame(mut frame: Bytes) -> Option<(u64, Bytes)> {
+    if frame.len() < WRITTEN_AT {
+        return None;
+    }
+    let payload = frame.split_off(WRITTEN_AT);
+    let mut written_at = [0u8; WRITTEN_AT];
+    written_at.copy_from_slice(&frame);
+    Some((u64::from_le_bytes(written_at), payload))
+}
@@ -1,147 +1,221 @@
+use super::archive::Archive;
+use super::backend::{Backend, BackendFile};
 use super::error::Error;
-use async_bincode::AsyncBincodeReader;
-use serde::Deserialize;
-use tokio_fs::File;
+use super::frame::{self, FrameReader};
+use super::record;
+use super::segment;
+use super::store::{LocalFs, SegmentStore, Watch};
+use bytes::Bytes;
 
 use futures::prelude::*;
 use futures::try_ready;
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
 
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use futures::sync::mpsc;
-use notify::{self, Watcher};
-use std::sync::mpsc as std_mpsc;
+/// Dir inside the dir with files where broken files are moved.
+pub const QUARANTINE_DIR: &str = "quarantine";
 
-type Rx<T> = mpsc::UnboundedReceiver<T>;
+/// Stream thats read raw items from file with monitoring changes.
+pub struct RawFileReciver {
+    reader: FrameReader<Box<dyn BackendFile>>,
+    store: Arc<dyn SegmentStore>,
+    index: usize,
+    path: PathBuf,
+    events_rx: Option<Watch>,
+    caught_up: bool,
+    ttl: Option<Duration>,
+    expired_items: u64,
+    // ordinal of the next item in the file.
+    position: u64,
+    keep_file: bool,
+    archive: Option<Archive>,
+    // offset of the last item returned.
+    item_offset: u64,
+    // size of the last item returned.
+    item_size: u64,
+}
 
-struct FileWatcher {
-    rx: Rx<notify::RawEvent>,
-    // we don't use them but prevent call drop.
-    _thread: std::thread::JoinHandle<()>,
-    _watcher: notify::RecommendedWatcher,
+pub fn new_raw(path: PathBuf) -> io::Result<RawFileReciver> {
+    open_raw(Arc::new(LocalFs::file(path)), 0)
 }
 
-impl FileWatcher {
-    pub fn watch_path<P: AsRef<Path>>(path: P) -> Result<FileWatcher, notify::Error> {
-        let (tx, rx) = mpsc::unbounded();
-        let (std_tx, std_rx) = std_mpsc::channel();
-        let thread = std::thread::spawn(move || {
-            while let Ok(item) = std_rx.recv() {
-                if let Err(err) = tx.unbounded_send(item) {
-                    // other side is droped.
-                    trace!("Reciver will never recive {:?}", err);
-                    break;
-                }
-            }
-        });
+/// Create reciver of segment `index` in `store`.
+fn open_raw(store: Arc<dyn SegmentStore>, index: usize) -> io::Result<RawFileReciver> {
+    Ok(RawFileReciver {
+        reader: FrameReader::new(store.open(index, 0)?, &store.path(index), 0),
+        path: store.path(index),
+        store,
+        index,
+        events_rx: None,
+        caught_up: false,
+        ttl: None,
+        expired_items: 0,
+        position: 0,
+        keep_file: false,
+        archive: None,
+        item_offset: 0,
+        item_size: 0,
+    })
+}
 
-        let mut watcher = notify::raw_watcher(std_tx)?;
-        watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
-        Ok(FileWatcher {
-            rx,
-            _thread: thread,
-            _watcher: watcher,
-        })
+impl RawFileReciver {
+    /// Path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
-}
 
-impl Stream for FileWatcher {
-    type Item = notify::RawEvent;
-    type Error = notify::Error;
-    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        let item = self.rx.poll();
-        match item {
-            Ok(Async::Ready(Some(event))) => {
-                if event.op.is_err() {
-                    Err(event.op.unwrap_err())
-                } else {
-                    Ok(Async::Ready(Some(event)))
-                }
-            }
-            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
-            Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(_) => unreachable!(),
-        }
+    /// Offset of the last item returned by the stream.
+    pub fn item_offset(&self) -> u64 {
+        self.item_offset
     }
-}
 
-/// Stream thats read items from file with monitoring changes.
-pub struct FileReciver<T> {
-    reader: AsyncBincodeReader<File, T>,
-    path: PathBuf,
-    events_rx: Option<FileWatcher>,
-}
+    /// Size of the last item returned by the stream.
+    pub fn item_size(&self) -> u64 {
+        self.item_size
+    }
 
-pub fn new<T>(path: PathBuf) -> io::Result<FileReciver<T>> {
-    let read_fd_std = std::fs::OpenOptions::new().read(true).open(path.clone())?;
-    let read_file = File::from_std(read_fd_std);
-    let reader: AsyncBincodeReader<File, T> = read_file.into();
-    Ok(FileReciver {
-        reader,
-        path,
-        events_rx: None,
-    })
-}
+    /// Ordinal of the next item read from the file. Expired items are counted too.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
 
-impl<T> FileReciver<T> {
-    fn poll_watcher(&mut self) -> Poll<Option<()>, notify::Error> {
-        debug_assert!(self.events_rx.is_some());
+    /// Move to item `n` in the file without decoding items before it. Returns ordinal of the item
+    /// reciver moved to, which is less than `n` if file has less items.
+    pub fn seek_to_item(&mut self, n: u64) -> io::Result<u64> {
+        let (ordinal, offset) = self.store.seek(self.index, n)?;
+        let file = self.store.open(self.index, offset)?;
+        self.reader = FrameReader::new(file, &self.path, offset);
+        self.position = ordinal;
+        self.caught_up = false;
+        Ok(ordinal)
+    }
+
+    /// Read the file with `backend` instead of `ThreadPool`, see `SegmentStore::set_backend`.
+    pub fn set_backend(&mut self, backend: Arc<dyn Backend>) -> io::Result<()> {
+        self.store.set_backend(backend);
+        let offset = self.reader.offset();
+        self.reader = FrameReader::new(self.store.open(self.index, offset)?, &self.path, offset);
+        Ok(())
+    }
+
+    /// Skip items older than `ttl`.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+
+    /// Don't remove the file when it is sealed and fully read.
+    pub fn set_keep_file(&mut self, keep_file: bool) {
+        self.keep_file = keep_file;
+    }
+
+    /// Move the file to `archive` instead of removing it when it is sealed and fully read.
+    pub fn set_archive(&mut self, archive: Archive) {
+        self.archive = Some(archive);
+    }
+
+    /// Number of items skipped because they were older than ttl.
+    pub fn expired_items(&self) -> u64 {
+        self.expired_items
+    }
+
+    /// Return true if all items written to the file so far are read and reciver waits for more.
+    pub fn is_caught_up(&self) -> bool {
+        self.caught_up
+    }
+
+    /// Start reading the current item again after error of the reader.
+    fn read_error(&mut self, err: io::Error) -> Error {
+        if let Err(seek_err) = self.seek_to_item(self.position) {
+            warn!("Can't read {:?} again: {}", self.path, seek_err);
+        }
+        Error::from_io(err)
+    }
 
-        let events_rx_mut = self.events_rx.as_mut().unwrap();
-        let async_result = events_rx_mut.poll()?.map(|file_event| {
-            debug!("Event from os {:?}", file_event);
-            Some(())
-        });
+    fn poll_watcher(&mut self) -> Poll<Option<()>, io::Error> {
+        debug_assert!(self.events_rx.is_some());
 
-        Ok(async_result)
+        self.events_rx.as_mut().unwrap().poll()
     }
 }
 
-impl<T> Stream for FileReciver<T>
-where
-    for<'a> T: Deserialize<'a>,
-{
-    type Item = T;
+impl Stream for RawFileReciver {
+    type Item = Bytes;
     type Error = Error;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
             trace!("poll reciver!");
             // tutaj jest zwracane None jesli jestesmy na koncu pliku.
-            let opt_item = match self.reader.poll()? {
-                Async::Ready(opt_item) => opt_item,
-                Async::NotReady => return Ok(Async::NotReady),
+            let offset = self.reader.offset();
+            let opt_frame = match self.reader.poll_frame() {
+                Ok(Async::Ready(opt_frame)) => opt_frame,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => return Err(self.read_error(err)),
             };
 
-            if opt_item.is_some() {
-                return Ok(Async::Ready(opt_item));
+            if let Some(frame) = opt_frame {
+                self.caught_up = false;
+                self.position += 1;
+                self.item_offset = offset;
+                let (written_at, item) = match frame::split_frame(frame) {
+                    Some(record) => record,
+                    None => {
+                        return Err(Error::Layout {
+                            path: self.path.clone(),
+                            reason: format!("item at offset {} is too short", offset),
+                        })
+                    }
+                };
+                match self.ttl {
+                    Some(ttl) if record::is_expired(written_at, ttl) => {
+                        trace!("Skipping expired item");
+                        self.expired_items += 1;
+                        continue;
+                    }
+                    _ => {
+                        self.item_size = item.len() as u64;
+                        return Ok(Async::Ready(Some(item)));
+                    }
+                }
             } else {
                 // check file is read_only:
                 // - yes -- return None
                 // - no  -- return NotReady - more data can be added.
 
-                // getting inner instane with is tokio_fs::File
                 // TODO replace using try_ready! when trace will be not necessery
-                let async_item = self.reader.get_mut().poll_metadata()?;
+                let async_item = self
+                    .reader
+                    .get_mut()
+                    .poll_is_sealed()
+                    .map_err(Error::from_io)?;
 
                 match async_item {
-                    Async::Ready(metadata) => {
-                        if metadata.permissions().readonly() {
+                    Async::Ready(sealed) => {
+                        if sealed {
                             trace!("File fully readed and marked readonly -- stream done!");
-                            //TODO remove file -- keep state when polling!
-                            //FIXME
-                            try_ready!(tokio_fs::remove_file(&self.path).poll());
+                            if !self.keep_file {
+                                match self.archive {
+                                    Some(ref archive) => try_ready!(archive
+                                        .poll_store(&self.path)
+                                        .map_err(Error::from_io)),
+                                    None => {
+                                        self.store.remove(self.index).map_err(Error::from_io)?
+                                    }
+                                }
+                            }
                             return Ok(Async::Ready(None));
                         } else {
                             trace!("Not ready - File not marked readonly!");
+                            self.caught_up = true;
                             // TODO task::current().notify();
                             // create FileWatcher and read notifications.
                             if self.events_rx.is_none() {
-                                let file_watcher =
-                                    FileWatcher::watch_path(&self.path).expect("Working watcher");
-                                self.events_rx = Some(file_watcher);
+                                self.events_rx =
+                                    Some(self.store.watch(self.index).map_err(Error::from_io)?);
                                 continue; // Sth could be added to file!
                             }
                         }
@@ -152,7 +226,9 @@ where
                 }
 
                 if self.events_rx.is_some() {
-                    while let Some(_notify_file_was_changed) = try_ready!(self.poll_watcher()) {
+                    while let Some(_notify_file_was_changed) =
+                        try_ready!(self.poll_watcher().map_err(Error::from_io))
+                    {
                         trace!("iterate over event");
                     }
                 }
@@ -161,64 +237,334 @@ where
     }
 }
 
-/// Stream to read serialized items `T` from dir.
-pub struct DirReciver<T> {
-    dir_path: PathBuf,
-    file: FileReciver<T>,
+/// Stream to read raw items from dir.
+pub struct RawDirReciver {
+    store: Arc<dyn SegmentStore>,
+    file: RawFileReciver,
     next_file_index: usize,
+    // false until current file is checked if it's expired.
+    file_checked: bool,
+    ttl: Option<Duration>,
+    expired_items: u64,
+    expired_files: u64,
+    keep_files: bool,
+    archive: Option<Archive>,
+    // true when the last file was read and there was no next file, see `poll`.
+    ended: bool,
 }
 
-pub fn new_dir_reciver<T>(dir_path: PathBuf) -> io::Result<DirReciver<T>> {
-    if !dir_path.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Path {:?} dosen't represent dir", dir_path),
-        ));
-    }
-
-    let next_file_index = 0; //TODO powinno byc sprawdzone czy nie ma juz plikow w katalogu.
+pub fn new_raw_dir_reciver(store: Arc<dyn SegmentStore>) -> io::Result<RawDirReciver> {
+    // Start from the oldest file.
+    let next_file_index = store.list()?.first().cloned().unwrap_or(0);
 
-    let mut file_path = dir_path.clone();
-    file_path.push(next_file_index.to_string());
-
-    Ok(DirReciver {
-        dir_path,
-        file: new(file_path)?,
+    Ok(RawDirReciver {
+        file: open_raw(store.clone(), next_file_index)?,
+        store,
         next_file_index: next_file_index + 1,
+        file_checked: false,
+        ttl: None,
+        expired_items: 0,
+        expired_files: 0,
+        keep_files: false,
+        archive: None,
+        ended: false,
     })
 }
 
-impl<T> DirReciver<T> {
-    fn use_next_file(&mut self) -> Result<Option<FileReciver<T>>, io::Error> {
-        let mut path = self.dir_path.clone();
-        path.push(self.next_file_index.to_string());
-        self.next_file_index += 1;
-
-        match new(path).map(Some) {
-            Err(err) => match err.kind() {
-                io::ErrorKind::NotFound => Ok(None),
-                _ => Err(err),
-            },
-            forward => forward,
+/// Return true if segment is sealed and it was modified before `ttl`, so all items inside are
+/// expired.
+fn is_file_expired(store: &dyn SegmentStore, index: usize, ttl: Duration) -> io::Result<bool> {
+    if !store.is_sealed(index)? {
+        // Sender can still append to the file.
+        return Ok(false);
+    }
+    let age = SystemTime::now()
+        .duration_since(store.modified(index)?)
+        .unwrap_or_default();
+    Ok(age > ttl)
+}
+
+impl RawDirReciver {
+    /// Path of the file with the last item returned and offset of the item there.
+    pub fn item_location(&self) -> (&Path, u64) {
+        (self.file.path(), self.file.item_offset())
+    }
+
+    /// Size of the last item returned by the stream.
+    pub fn item_size(&self) -> u64 {
+        self.file.item_size()
+    }
+
+    /// Read files with `backend` instead of `ThreadPool`. Backend is set for the store, so a
+    /// sender of the same store uses it for next files too.
+    pub fn set_backend(&mut self, backend: Arc<dyn Backend>) -> io::Result<()> {
+        self.file.set_backend(backend)
+    }
+
+    /// Skip items older than `ttl`. Files with all items expired are removed without reading
+    /// them.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+        self.file.set_ttl(ttl);
+    }
+
+    /// Don't remove or modify anything in the dir. Read files and expired files are only skipped,
+    /// so the dir can be read again later.
+    pub fn set_keep_files(&mut self, keep_files: bool) {
+        self.keep_files = keep_files;
+        self.file.set_keep_file(keep_files);
+    }
+
+    /// Move fully read, expired and skipped files to `archive` instead of removing them.
+    ///
+    /// # Errors
+    /// Returns `InvalidInput` if segments are not stored on local fs.
+    pub fn set_archive(&mut self, archive: Archive) -> io::Result<()> {
+        if self.store.dir_path().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Archive needs segments stored on local fs",
+            ));
+        }
+        self.file.set_archive(archive.clone());
+        self.archive = Some(archive);
+        Ok(())
+    }
+
+    /// Remove segment `index` or move it to the archive.
+    fn discard_file(&self, index: usize) -> io::Result<()> {
+        match self.archive {
+            Some(ref archive) => archive.store(&self.store.path(index)),
+            None => self.store.remove(index),
+        }
+    }
+
+    /// Like `discard_file`, but archiving doesn't block the task.
+    fn poll_discard_file(&self, index: usize) -> Poll<(), io::Error> {
+        match self.archive {
+            Some(ref archive) => archive.poll_store(&self.store.path(index)),
+            None => self.store.remove(index).map(Async::Ready),
+        }
+    }
+
+    /// Number of items skipped because they were older than ttl. Items from removed expired files
+    /// are not counted, see `expired_files`.
+    pub fn expired_items(&self) -> u64 {
+        self.expired_items + self.file.expired_items()
+    }
+
+    /// Number of files removed (or skipped if files are kept) without reading because all items
+    /// inside were expired.
+    pub fn expired_files(&self) -> u64 {
+        self.expired_files
+    }
+
+    /// Remove current file if all items inside are expired. Returns true if file was removed or
+    /// should be skipped when files are kept.
+    fn poll_remove_expired_file(&mut self) -> Poll<bool, io::Error> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => {
+                self.file_checked = true;
+                return Ok(Async::Ready(false));
+            }
+        };
+
+        let expired = is_file_expired(&*self.store, self.file.index, ttl)?;
+        if expired {
+            if self.keep_files {
+                debug!("Skipping expired file {:?}", self.file.path);
+            } else {
+                debug!("Removing expired file {:?}", self.file.path);
+                try_ready!(self.poll_discard_file(self.file.index));
+            }
+            self.expired_files += 1;
+        }
+        self.file_checked = true;
+        Ok(Async::Ready(expired))
+    }
+
+    /// Continue with `file` configured like the current one.
+    fn set_file(&mut self, mut file: RawFileReciver) {
+        self.expired_items += self.file.expired_items();
+        if let Some(ttl) = self.ttl {
+            file.set_ttl(ttl);
+        }
+        file.set_keep_file(self.keep_files);
+        if let Some(ref archive) = self.archive {
+            file.set_archive(archive.clone());
+        }
+        self.file = file;
+        self.file_checked = false;
+        self.ended = false;
+    }
+
+    /// Switch to the next file. Returns false if there is no next file.
+    ///
+    /// # Errors
+    /// Returns `Error::Layout` if the next file is missing but there are files after it.
+    fn switch_file(&mut self) -> Result<bool, Error> {
+        match self.use_next_file().map_err(Error::from_io)? {
+            Some(file) => {
+                self.set_file(file);
+                Ok(true)
+            }
+            None => {
+                let next_file_index = self.next_file_index;
+                if let Some(&index) = self
+                    .store
+                    .list()
+                    .map_err(Error::from_io)?
+                    .iter()
+                    .find(|&&index| index > next_file_index)
+                {
+                    return Err(Error::Layout {
+                        path: self.store.path(next_file_index),
+                        reason: format!(
+                            "file {} is missing, next file is {}",
+                            next_file_index, index
+                        ),
+                    });
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Return true if all items written to the dir so far are read and reciver waits for more.
+    pub fn is_caught_up(&self) -> bool {
+        self.file.is_caught_up()
+    }
+
+    /// Skip `n` items without decoding them. Sealed files skipped entirely are removed unless files
+    /// are kept. Returns number of skipped items, which is less than `n` if there are not enough
+    /// items in the dir.
+    pub fn skip_items(&mut self, n: u64) -> Result<u64, Error> {
+        let mut skipped = 0;
+        loop {
+            let position = self.file.position();
+            skipped += self
+                .file
+                .seek_to_item(position + (n - skipped))
+                .map_err(Error::from_io)?
+                - position;
+            if !self
+                .store
+                .is_sealed(self.file.index)
+                .map_err(Error::from_io)?
+            {
+                return Ok(skipped);
+            }
+            // a sealed file read to the end is removed now, not when the next item is read.
+            let position = self.file.position();
+            if skipped == n
+                && self
+                    .store
+                    .seek(self.file.index, position + 1)
+                    .map_err(Error::from_io)?
+                    .0
+                    > position
+            {
+                return Ok(skipped);
+            }
+
+            let old_index = self.file.index;
+            if !self.switch_file()? {
+                return Ok(skipped);
+            }
+            if !self.keep_files {
+                debug!("Removing skipped file {:?}", self.store.path(old_index));
+                self.discard_file(old_index).map_err(Error::from_io)?;
+            }
+        }
+    }
+
+    /// Move the current file to `quarantine` dir inside the dir and continue with the next file.
+    /// Returns false if the file can't be moved because sender can still append to it or there
+    /// is no next file.
+    ///
+    /// # Errors
+    /// Returns `InvalidInput` if segments are not stored on local fs.
+    pub fn quarantine_file(&mut self) -> Result<bool, Error> {
+        let quarantine_dir = match self.store.dir_path() {
+            Some(dir_path) => dir_path.join(QUARANTINE_DIR),
+            None => {
+                return Err(Error::from_io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Quarantine needs segments stored on local fs",
+                )))
+            }
+        };
+        if !self
+            .store
+            .is_sealed(self.file.index)
+            .map_err(Error::from_io)?
+        {
+            return Ok(false);
+        }
+        let old_path = self.file.path.clone();
+        if !self.switch_file()? {
+            return Ok(false);
+        }
+
+        std::fs::create_dir_all(&quarantine_dir).map_err(Error::from_io)?;
+        let name = format!(
+            "{}-{}",
+            record::now_millis(),
+            old_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+        );
+        warn!("Moving {:?} to quarantine", old_path);
+        std::fs::rename(&old_path, quarantine_dir.join(name)).map_err(Error::from_io)?;
+        segment::remove_index(&old_path);
+        Ok(true)
+    }
+
+    fn use_next_file(&mut self) -> Result<Option<RawFileReciver>, io::Error> {
+        match open_raw(self.store.clone(), self.next_file_index) {
+            Ok(file) => {
+                self.next_file_index += 1;
+                Ok(Some(file))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
         }
     }
 }
 
-impl<T> Stream for DirReciver<T>
-where
-    for<'a> T: Deserialize<'a>,
-{
-    type Item = T;
+/// Stream ends when all files are read and the last one is sealed. It can be polled again, items
+/// of files created after that are read then.
+impl Stream for RawDirReciver {
+    type Item = Bytes;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.ended {
+            // only the next file is checked, missing files were reported when the stream ended.
+            match self.use_next_file().map_err(Error::from_io)? {
+                Some(file) => self.set_file(file),
+                None => return Ok(Async::Ready(None)),
+            }
+        }
         loop {
+            if !self.file_checked
+                && try_ready!(self.poll_remove_expired_file().map_err(Error::from_io))
+            {
+                if !self.switch_file()? {
+                    self.ended = true;
+                    return Ok(Async::Ready(None));
+                }
+                continue;
+            }
+
             match try_ready!(self.file.poll()) {
                 None => {
-                    match self.use_next_file()? {
-                        Some(file) => self.file = file,
-                        None => return Ok(Async::Ready(None)),
-                    };
+                    if !self.switch_file()? {
+                        self.ended = true;
+                        return Ok(Async::Ready(None));
+                    }
                 }
                 some_item => return Ok(Async::Ready(some_item)),
             }
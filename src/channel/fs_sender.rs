@@ -1,29 +1,63 @@
-use async_bincode::{AsyncBincodeWriter, AsyncDestination, SyncDestination};
-// use bincode::Error;
-use super::error::{self, Error};
-use super::fs_receiver::DirReciver;
+use super::backend::{self, Backend, BackendFile};
+use super::backoff::Backoff;
+use super::breaker::{Breaker, CircuitBreaker};
+use super::codec::{DirReciver, DirSender};
+use super::dead_letter::DeadLetters;
+use super::error::Error;
+use super::frame::FrameWriter;
+use super::group_commit::GroupCommit;
+use super::rate_limit::{RateLimit, RateUnit, TokenBucket};
+use super::record;
+use super::segment;
+use super::store::{LocalFs, SegmentStore};
+use bytes::Bytes;
 use custom_error::{add_type_bounds, custom_error};
 use futures::prelude::*;
+use futures::sync::oneshot;
 use futures::{stream::Fuse, try_ready};
-use log::trace;
+use log::{trace, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io;
-use std::path::PathBuf;
-use tokio_fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
-/// Unbounded Sender through file that will be saving all item until fs limit.
-pub struct UnboundedFileSender<T> {
-    writer: AsyncBincodeWriter<File, T, AsyncDestination>,
+/// Unbounded Sender of raw items through file that will be saving all item until fs limit.
+///
+/// Items are buffered in memory until `poll_complete`, so `start_send` is always ready unless
+/// `group_commit` is set.
+pub struct RawFileSender {
+    writer: FrameWriter<Box<dyn BackendFile>>,
+    // store with the file, it seals the file when it's closed.
+    store: Arc<dyn SegmentStore>,
+    segment: usize,
     closing: ClosingFile,
+    group_commit: Option<GroupCommit>,
+    // started with the first buffered item.
+    linger: Option<Delay>,
+    // true if written items are not synced yet.
+    unsynced: bool,
+    // true if disk space beyond the items is reserved, it's released when file is sealed.
+    preallocated: bool,
+    // sparse index of item offsets, see `SegmentStore::seek`.
+    index: Box<dyn io::Write + Send>,
+    // index entries of buffered items, written after the items.
+    index_entries: Vec<u8>,
+    items: u64,
+    bytes: u64,
+    // size of the segment header, items are stored after it.
+    header: u64,
 }
 
 #[derive(PartialEq, Eq)]
 enum ClosingFile {
     None,
     PollComplete,
-    ReadPermision,
-    WritePerminison(std::fs::Permissions),
+    Truncate,
+    Seal,
     Writer,
 }
 
@@ -34,36 +68,135 @@ enum ClosingFile {
 ///
 /// # Warning
 /// It's logical error to use file that already exist on file system with unknow body.
-pub fn unbounded<T>(path: &PathBuf) -> io::Result<UnboundedFileSender<T>> {
-    let write_fd_std = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)?;
-
-    let write_file = File::from_std(write_fd_std);
-    let writer: AsyncBincodeWriter<File, T, SyncDestination> = write_file.into();
-    Ok(UnboundedFileSender {
-        writer: writer.for_async(),
+pub fn unbounded_raw(path: &Path) -> io::Result<RawFileSender> {
+    let store: Arc<dyn SegmentStore> = Arc::new(LocalFs::file(path.to_path_buf()));
+    store.create(0)?;
+    new_raw(store, 0)
+}
+
+/// Create sender appending to segment `index` opened by `SegmentStore::append`.
+fn new_raw(store: Arc<dyn SegmentStore>, index: usize) -> io::Result<RawFileSender> {
+    let appender = store.append(index)?;
+    Ok(RawFileSender {
+        writer: FrameWriter::new(appender.file),
+        store,
+        segment: index,
         closing: ClosingFile::None,
+        group_commit: None,
+        linger: None,
+        unsynced: false,
+        preallocated: false,
+        index: appender.index,
+        index_entries: Vec::new(),
+        items: appender.items,
+        bytes: appender.bytes,
+        header: appender.header,
     })
 }
 
-impl<T> Sink for UnboundedFileSender<T>
-where
-    T: Serialize,
-{
+impl RawFileSender {
+    /// Number of items stored in the file, including items that were there before.
+    pub fn items(&self) -> u64 {
+        self.items
+    }
+
+    /// Collect items and write them together, see `GroupCommit`.
+    pub fn group_commit(mut self, group_commit: GroupCommit) -> Self {
+        self.group_commit = Some(group_commit);
+        self
+    }
+
+    /// Add serialized item to the file.
+    pub fn push(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if let Some(ref group_commit) = self.group_commit {
+            if self.writer.buffered() == 0 && group_commit.linger > Duration::from_secs(0) {
+                self.linger = Some(Delay::new(Instant::now() + group_commit.linger));
+            }
+            self.unsynced |= group_commit.fsync;
+        }
+        let size = self
+            .writer
+            .push(record::now_millis(), payload)
+            .map_err(Error::from_io)?;
+        if self.items % segment::INDEX_INTERVAL == 0 {
+            segment::write_index_entry(&mut self.index_entries, self.items, self.bytes)
+                .map_err(Error::from_io)?;
+        }
+        self.items += 1;
+        self.bytes += size;
+        Ok(())
+    }
+
+    /// Ready when next item can be pushed. With group commit buffered items are written first if
+    /// they take `max_bytes` already.
+    pub fn poll_ready(&mut self) -> Poll<(), Error> {
+        match self.group_commit {
+            Some(ref group_commit) if self.writer.buffered() >= group_commit.max_bytes => {
+                self.poll_commit()
+            }
+            _ => Ok(Async::Ready(())),
+        }
+    }
+
+    /// Return true if buffered items should be written now, not after linger.
+    fn is_commit_due(&mut self) -> bool {
+        let group_commit = match self.group_commit {
+            Some(ref group_commit) => group_commit,
+            None => return true,
+        };
+        if self.writer.buffered() >= group_commit.max_bytes {
+            return true;
+        }
+        match self.linger.as_mut().map(Future::poll) {
+            Some(Ok(Async::NotReady)) => false,
+            Some(Err(err)) => {
+                warn!("Timer failed: {}, writing items", err);
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Write buffered items with one write and sync the file if enabled.
+    fn poll_commit(&mut self) -> Poll<(), Error> {
+        try_ready!(self.writer.poll_flush().map_err(Error::from_io));
+        self.linger = None;
+        if !self.index_entries.is_empty() {
+            let (index, entries) = (&mut self.index, &self.index_entries);
+            try_ready!(backend::blocking(|| index.write_all(entries)).map_err(Error::from_io));
+            self.index_entries.clear();
+        }
+        if self.unsynced {
+            try_ready!(self
+                .writer
+                .get_mut()
+                .poll_sync_data()
+                .map_err(Error::from_io));
+            self.unsynced = false;
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Sink for RawFileSender {
     /// The type of value that the sink accepts.
-    type SinkItem = T;
+    type SinkItem = Bytes;
 
     /// The type of value produced by the sink when an error occurs.
-    type SinkError = bincode::Error;
+    type SinkError = Error;
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        self.writer.start_send(item)
-        //self.writer.start_send(Some(item)).map(|async_sink| async_sink.map(|opt_item| opt_item.unwrap()))
+        if self.poll_ready()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.push(&item)?;
+        Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.writer.poll_complete()
+        if !self.is_commit_due() {
+            return Ok(Async::NotReady);
+        }
+        self.poll_commit()
     }
 
     //
@@ -81,217 +214,539 @@ where
                 ClosingFile::None => unreachable!(),
                 ClosingFile::PollComplete => {
                     trace!("Close is called -> PollComplete");
-                    try_ready!(self.poll_complete());
-                    self.closing = ClosingFile::ReadPermision;
+                    try_ready!(self.poll_commit());
+                    self.closing = ClosingFile::Truncate;
                 }
-                ClosingFile::ReadPermision => {
-                    trace!("Close is called -> ReadPermision");
-                    let mut perms = try_ready!(self.writer.get_mut().poll_metadata()).permissions();
-                    perms.set_readonly(true);
-                    self.closing = ClosingFile::WritePerminison(perms);
+                ClosingFile::Truncate => {
+                    if self.preallocated {
+                        trace!("Close is called -> Truncate");
+                        try_ready!(self
+                            .writer
+                            .get_mut()
+                            .poll_set_len(self.header + self.bytes)
+                            .map_err(Error::from_io));
+                        self.preallocated = false;
+                    }
+                    self.closing = ClosingFile::Seal;
                 }
-                ClosingFile::WritePerminison(ref perms) => {
-                    trace!("Close is called -> WritePerminison");
-                    try_ready!(self.writer.get_mut().poll_set_permissions(perms.clone()));
+                ClosingFile::Seal => {
+                    trace!("Close is called -> Seal");
+                    let (store, segment) = (&self.store, self.segment);
+                    try_ready!(backend::blocking(|| store.seal(segment)).map_err(Error::from_io));
                     self.closing = ClosingFile::Writer;
                 }
                 ClosingFile::Writer => {
                     trace!("Close is called -> Writer");
-                    return self.writer.close();
+                    return self.writer.poll_close().map_err(Error::from_io);
                 }
             }
         }
     }
 }
 
-enum WrapError<T> {
-    MaxItems(T),
-    BinCodeError(bincode::Error),
-}
-
-impl<T> From<bincode::Error> for WrapError<T> {
-    fn from(oth: bincode::Error) -> Self {
-        WrapError::BinCodeError(oth)
-    }
-}
-
-struct FileSender<T> {
-    file: UnboundedFileSender<T>,
+struct FileSender {
+    file: RawFileSender,
     number_of_items: usize,
     max_number_of_items: usize,
 }
 
-fn new_file_sender<T>(path: PathBuf, max_number_of_items: usize) -> io::Result<FileSender<T>> {
+/// Create sender to segment `index` in `store`. If it already exists items are appended after
+/// items stored there.
+///
+/// # Errors
+/// Returns `PermissionDenied` if segment is sealed or error of preallocation, see
+/// `RawDirSender::preallocate`.
+fn new_file_sender(
+    store: &Arc<dyn SegmentStore>,
+    index: usize,
+    max_number_of_items: usize,
+    preallocate: Option<u64>,
+) -> io::Result<FileSender> {
     let max_number_of_items = if max_number_of_items == 0 {
-        usize::max_value()
+        usize::MAX
     } else {
         max_number_of_items
     };
 
-    let number_of_items = if path.exists() {
-        0
-    // TODO read how many items are in file and
-    } else {
-        0
-    };
+    if store.is_sealed(index)? {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("File {:?} is sealed", store.path(index)),
+        ));
+    }
 
-    let file = unbounded(&path)?;
+    store.create(index)?;
+    let mut file = new_raw(store.clone(), index)?;
+    if let Some(len) = preallocate {
+        store.preallocate(index, len)?;
+        file.preallocated = true;
+    }
 
     Ok(FileSender {
+        number_of_items: file.items() as usize,
         file,
-        number_of_items,
         max_number_of_items,
     })
 }
 
-impl<T> Sink for FileSender<T>
-where
-    T: Serialize,
-{
-    /// The type of value that the sink accepts.
-    type SinkItem = T;
-
-    /// The type of value produced by the sink when an error occurs.
-    type SinkError = WrapError<T>;
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        if self.max_number_of_items <= self.number_of_items {
-            return Err(WrapError::MaxItems(item));
-        }
-
-        Ok(match self.file.start_send(item)? {
-            AsyncSink::Ready => {
-                self.number_of_items += 1;
-                AsyncSink::Ready
-            }
-            not_ready => not_ready,
-        })
-    }
-
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.file.poll_complete().map_err(WrapError::from)
+impl FileSender {
+    fn is_full(&self) -> bool {
+        self.max_number_of_items <= self.number_of_items
     }
 
-    fn close(&mut self) -> Poll<(), Self::SinkError> {
-        self.file.close().map_err(WrapError::from)
+    fn push(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.file.push(payload)?;
+        self.number_of_items += 1;
+        Ok(())
     }
 }
 
-pub struct DirSender<T> {
-    dir_path: PathBuf,
-    file: FileSender<T>,
-    next_file_index: u32,
+/// Sender of raw items to a dir. It's creating next file after last one is full.
+///
+/// Items are buffered in memory until `poll_complete`, so `start_send` is always ready unless
+/// `group_commit` is set.
+pub struct RawDirSender {
+    store: Arc<dyn SegmentStore>,
+    // `None` when the file is closed or the last file was sealed, next item starts a new file.
+    file: Option<FileSender>,
+    // full files that are being closed.
+    sealing: Vec<FileSender>,
+    next_file_index: usize,
     max_number_of_items: usize,
+    preallocate: Option<u64>,
+    group_commit: Option<GroupCommit>,
 }
 
-pub fn new_dir_sender<T>(
-    dir_path: PathBuf,
-    max_number_of_items: usize,
-) -> io::Result<DirSender<T>> {
-    if !dir_path.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Path {:?} dosen't represent dir", dir_path),
-        ));
+/// Index of first segment that is not sealed, starting from `index`. It's the one after the last
+/// segment if all are sealed.
+///
+/// # Errors
+/// Returns `InvalidData` with `Error::Layout` inside if an unsealed segment comes before a sealed
+/// one, a reader would wait for the end of it forever.
+fn first_unsealed(store: &dyn SegmentStore, index: usize) -> io::Result<usize> {
+    let indexes: Vec<usize> = store.list()?.into_iter().filter(|&i| i >= index).collect();
+    let mut unsealed = None;
+    for &i in &indexes {
+        match (store.is_sealed(i)?, unsealed) {
+            (true, Some(unsealed)) => {
+                return Err(Error::layout_io(
+                    &store.path(unsealed),
+                    format!("segment is not sealed, but segment {} after it is", i),
+                ))
+            }
+            (false, None) => unsealed = Some(i),
+            _ => (),
+        }
     }
+    Ok(unsealed.unwrap_or_else(|| indexes.last().map_or(index, |last| last + 1)))
+}
 
-    let next_file_index = 0; //TODO powinno byc sprawdzone czy nie ma juz plikow w katalogu.
-
-    let mut file_path = dir_path.clone();
-    file_path.push(next_file_index.to_string());
+pub fn new_raw_dir_sender(
+    store: Arc<dyn SegmentStore>,
+    max_number_of_items: usize,
+) -> io::Result<RawDirSender> {
+    // Continue with the last file, items written by previous run are still there. When it is
+    // sealed, the next file is created with the first item so a reader can reach the end of the
+    // dir.
+    let last_file_index = store.list()?.last().cloned().unwrap_or(0);
+    let file_index = first_unsealed(&*store, 0)?;
 
     let max_number_of_items = if max_number_of_items == 0 {
-        usize::max_value()
+        usize::MAX
     } else {
         max_number_of_items
     };
 
-    Ok(DirSender {
-        dir_path,
-        file: new_file_sender(file_path, max_number_of_items)?,
-        next_file_index: next_file_index + 1,
+    let file = if file_index == last_file_index {
+        Some(new_file_sender(
+            &store,
+            file_index,
+            max_number_of_items,
+            None,
+        )?)
+    } else {
+        None
+    };
+
+    Ok(RawDirSender {
+        next_file_index: if file.is_some() {
+            file_index + 1
+        } else {
+            file_index
+        },
+        file,
+        store,
+        sealing: Vec::new(),
         max_number_of_items,
+        preallocate: None,
+        group_commit: None,
     })
 }
 
-impl<T> DirSender<T> {
-    fn next_path(&self) -> PathBuf {
-        let mut path = self.dir_path.clone();
-        path.push(format!("{}", self.next_file_index));
-        path
+impl RawDirSender {
+    /// Path of the dir, `None` if segments are not stored on local fs.
+    pub fn dir_path(&self) -> Option<&Path> {
+        self.store.dir_path()
+    }
+
+    /// Reserve `len` bytes on disk for every file, e.g. expected size of a full file. Files are
+    /// not fragmented and missing space is reported when the next file is created, not in the
+    /// middle of a write. Space that is not used is released when a file is sealed.
+    ///
+    /// A full file takes 8 bytes of header and `12 + item size` bytes for every one of
+    /// `max_items_in_file` items, where 12 bytes are the size prefix and the write time and item
+    /// size is its encoded size. Nothing is reserved if file system doesn't support it.
+    ///
+    /// # Errors
+    /// Returns error if there is not enough space on disk for the current file.
+    pub fn preallocate(mut self, len: u64) -> io::Result<Self> {
+        if let Some(file) = &mut self.file {
+            self.store.preallocate(self.next_file_index - 1, len)?;
+            file.file.preallocated = true;
+        }
+        self.preallocate = Some(len);
+        Ok(self)
+    }
+
+    /// Collect items and write them together, see `GroupCommit`. It applies to every file.
+    pub fn group_commit(mut self, group_commit: GroupCommit) -> Self {
+        if let Some(file) = &mut self.file {
+            file.file.group_commit = Some(group_commit.clone());
+        }
+        self.group_commit = Some(group_commit);
+        self
+    }
+
+    /// Ready when next item can be pushed, see `RawFileSender::poll_ready`.
+    pub fn poll_ready(&mut self) -> Poll<(), Error> {
+        match &mut self.file {
+            Some(file) => file.file.poll_ready(),
+            None => Ok(Async::Ready(())),
+        }
+    }
+
+    /// Read and write files with `backend` instead of `ThreadPool`. Call it before sending items,
+    /// the current file is opened again. Backend is set for the store, so a reciver of the same
+    /// store uses it for next files too, see `SegmentStore::set_backend`.
+    pub fn backend(mut self, backend: Arc<dyn Backend>) -> io::Result<Self> {
+        self.store.set_backend(backend);
+        if self.file.is_some() {
+            let mut file = new_file_sender(
+                &self.store,
+                self.next_file_index - 1,
+                self.max_number_of_items,
+                self.preallocate,
+            )?;
+            file.file.group_commit = self.group_commit.clone();
+            self.file = Some(file);
+        }
+        Ok(self)
+    }
+
+    /// Add serialized item to the current file or to the next one if it is full.
+    pub fn push(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let file = match self.file.take() {
+            Some(file) if file.is_full() => {
+                self.sealing.push(file);
+                let next = self.next_file_sender().map_err(Error::from_io)?;
+                self.poll_sealing()?;
+                next
+            }
+            Some(file) => file,
+            // sending after close, the closed file is sealed already.
+            None => self.next_file_sender().map_err(Error::from_io)?,
+        };
+        self.file.get_or_insert(file).push(payload)
+    }
+
+    fn next_file_sender(&mut self) -> io::Result<FileSender> {
+        let index = first_unsealed(&*self.store, self.next_file_index)?;
+        trace!("DirSender -> switching to {:?}", self.store.path(index));
+        let mut next = new_file_sender(
+            &self.store,
+            index,
+            self.max_number_of_items,
+            self.preallocate,
+        )?;
+        next.file.group_commit = self.group_commit.clone();
+        self.next_file_index = index + 1;
+        Ok(next)
+    }
+
+    /// Close full files. Ready when all of them are closed.
+    fn poll_sealing(&mut self) -> Poll<(), Error> {
+        let mut i = 0;
+        while i < self.sealing.len() {
+            if self.sealing[i].file.close()?.is_ready() {
+                self.sealing.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.sealing.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 }
 
-impl<T> Sink for DirSender<T>
-where
-    T: Serialize,
-{
+impl Sink for RawDirSender {
     /// The type of value that the sink accepts.
-    type SinkItem = T;
+    type SinkItem = Bytes;
 
     /// The type of value produced by the sink when an error occurs.
     type SinkError = Error;
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        match self.file.start_send(item) {
-            Err(err) => match err {
-                WrapError::MaxItems(item) => {
-                    let next_path = self.next_path();
-                    let file = new_file_sender(next_path, self.max_number_of_items)?;
-                    self.file = file;
-                    self.start_send(item)
-                }
-                WrapError::BinCodeError(err) => return Err(err.into()),
-            },
-            Ok(ok) => return Ok(ok),
+        if self.poll_ready()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
         }
+        self.push(&item)?;
+        Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.file.poll_complete().map_err(|err| match err {
-            WrapError::MaxItems(_) => unreachable!(),
-            WrapError::BinCodeError(err) => Error::from(err),
-        })
+        let sealing = self.poll_sealing()?;
+        if let Some(file) = &mut self.file {
+            try_ready!(file.file.poll_complete());
+        }
+        Ok(sealing)
     }
 
     fn close(&mut self) -> Poll<(), Self::SinkError> {
-        self.file.close().map_err(|err| match err {
-            WrapError::MaxItems(_) => unreachable!(),
-            WrapError::BinCodeError(err) => Error::from(err),
-        })
+        let sealing = self.poll_sealing()?;
+        if let Some(file) = &mut self.file {
+            try_ready!(file.file.close());
+            self.file = None;
+        }
+        Ok(sealing)
+    }
+}
+
+/// Where [SendAllUnorderedFs](struct.SendAllUnorderedFs.html) writes items the sink didn't
+/// accept, a dir or lanes.
+pub trait SpillSender<T>: Sink<SinkItem = T, SinkError = Error> {
+    /// Like `start_send` but a quota is not checked. It's used for items that can't be given back
+    /// to the stream: items failed in the sink and items kept in memory on shutdown.
+    fn start_send_unbounded(&mut self, item: T) -> StartSend<T, Error> {
+        self.start_send(item)
+    }
+}
+
+/// Reads items written by a [SpillSender](trait.SpillSender.html).
+pub trait SpillReciver<T>: Stream<Item = T, Error = Error> {
+    /// Return true if all items written so far are read and reciver waits for more.
+    fn is_caught_up(&self) -> bool;
+
+    /// Size of the last item returned by the stream, when it was serialized.
+    fn item_size(&self) -> u64;
+
+    /// Move the file that can't be read to quarantine, see `RawDirReciver::quarantine_file`.
+    fn quarantine_file(&mut self) -> Result<bool, Error>;
+
+    /// Drop items the sender evicted, see `Eviction::DropOldest`. It's called when items written
+    /// to the dir are flushed, even if the sink doesn't take items read from the dir.
+    fn evict(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: Serialize> SpillSender<T> for DirSender<T> {}
+
+impl<T> SpillReciver<T> for DirReciver<T>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    fn is_caught_up(&self) -> bool {
+        DirReciver::is_caught_up(self)
+    }
+
+    fn item_size(&self) -> u64 {
+        DirReciver::item_size(self)
+    }
+
+    fn quarantine_file(&mut self) -> Result<bool, Error> {
+        DirReciver::quarantine_file(self)
     }
 }
 
-pub fn new_send_all<T, U>(
+pub fn new_send_all<T, U, S, R>(
     sink: T,
     stream: U,
-    dir_sender: DirSender<T::SinkItem>,
-    dir_reciver: DirReciver<T::SinkItem>,
-) -> SendAllUnorderedFs<T, U>
+    dir_sender: S,
+    dir_reciver: R,
+) -> SendAllUnorderedFs<T, U, S, R>
 where
     T: Sink,
     U: Stream<Item = T::SinkItem>,
     T::SinkError: From<U::Error>,
     T::SinkItem: Serialize + DeserializeOwned,
+    S: SpillSender<T::SinkItem>,
+    R: SpillReciver<T::SinkItem>,
 {
     SendAllUnorderedFs {
         sink: Some(sink),
         dir_sender,
-        dir_reciver: dir_reciver.fuse(),
+        dir_reciver,
+        dir_ended: false,
         stream: Some(stream.fuse()),
         buffered: None,
+        replayed: None,
         stream_closed: Closing::Working,
         check_fs_required: true,
+        dir_flushed: true,
+        drain_policy: DrainPolicy::Interleave,
+        rate_limit: None,
+        replay_delay: None,
+        shutdown: None,
+        error_handler: None,
+        error_attempt: 0,
+        read_failed: false,
+        store_retry: None,
+        sink_errors: None,
+        take_failed: None,
+        take_pending: None,
+        send_sized: None,
+        failed: VecDeque::new(),
+        dead_letters: None,
+        breaker: None,
     }
 }
 
-pub struct SendAllUnorderedFs<T: Sink, U> {
+pub struct SendAllUnorderedFs<
+    T: Sink,
+    U,
+    S = DirSender<<T as Sink>::SinkItem>,
+    R = DirReciver<<T as Sink>::SinkItem>,
+> {
     sink: Option<T>,
-    dir_sender: DirSender<T::SinkItem>,
-    dir_reciver: Fuse<DirReciver<T::SinkItem>>,
+    dir_sender: S,
+    dir_reciver: R,
+    // true if the dir reciver ended, it's polled again when items are written to the dir.
+    dir_ended: bool,
     // TODO we should guarantee that stream will not panic when called poll after returned None.
     stream: Option<Fuse<U>>,
-    buffered: Option<T::SinkItem>, // item, ktory nie mogl zostac odrzucony
+    buffered: Option<T::SinkItem>, // item from stream that neither sink nor dir accepted.
+    // Item read from dir that sink didn't accept. It's never written back to the dir, it is
+    // still on disk since file is removed only after it is fully read.
+    replayed: Option<T::SinkItem>,
     stream_closed: Closing,
+    // true until the dir reciver caught up with all items written to the dir.
     check_fs_required: bool,
+    dir_flushed: bool,
+    drain_policy: DrainPolicy,
+    rate_limit: Option<TokenBucket>,
+    // Some when replayed item waits for rate limit tokens.
+    replay_delay: Option<Delay>,
+    shutdown: Option<oneshot::Receiver<()>>,
+    error_handler: Option<Box<dyn StoreErrorHandler>>,
+    // number of store errors in a row.
+    error_attempt: usize,
+    // true if the last store error was returned by the dir reciver.
+    read_failed: bool,
+    // Some when waiting to retry after store error.
+    store_retry: Option<Delay>,
+    sink_errors: Option<SinkErrors<T>>,
+    take_failed: Option<TakeFailed<T>>,
+    // takes items the sink accepted but keeps in memory, they are written to the dir on shutdown.
+    take_pending: Option<TakePending<T>>,
+    // sends items read from the dir with their size known from the dir.
+    send_sized: Option<SendSized<T>>,
+    // items taken from sink errors, waiting to be written to the dir.
+    failed: VecDeque<T::SinkItem>,
+    dead_letters: Option<(DeadLetters<T::SinkItem>, TakeRejected<T>)>,
+    breaker: Option<Breaker>,
+}
+
+type IsTransient<E> = Box<dyn FnMut(&E) -> bool + Send>;
+type TakeFailed<T> =
+    fn(<T as Sink>::SinkError) -> Result<<T as Sink>::SinkItem, <T as Sink>::SinkError>;
+type TakePending<T> = fn(&mut T) -> Vec<<T as Sink>::SinkItem>;
+type SendSized<T> = fn(
+    &mut T,
+    <T as Sink>::SinkItem,
+    u64,
+) -> StartSend<<T as Sink>::SinkItem, <T as Sink>::SinkError>;
+type TakeRejected<T> =
+    fn(<T as Sink>::SinkError) -> Result<(<T as Sink>::SinkItem, String), <T as Sink>::SinkError>;
+
+/// Transient errors of the sink, see `SendAllUnorderedFs::spill_on_sink_error`.
+struct SinkErrors<T: Sink> {
+    is_transient: IsTransient<T::SinkError>,
+    clone_item: fn(&T::SinkItem) -> T::SinkItem,
+    backoff: Backoff,
+    // number of transient errors in a row.
+    attempt: u32,
+    // Some when sink is not used after error.
+    delay: Option<Delay>,
+}
+
+/// Order in which items from the dir and items from the stream are sent to the sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrainPolicy {
+    /// Send all items stored in the dir before any item from the stream. New items from the
+    /// stream are written to the dir as long as it is not empty so the order is kept.
+    BacklogFirst,
+    /// Send items from the dir and from the stream as they come. This is the default.
+    #[default]
+    Interleave,
+    /// Send items from the stream first. Items from the dir are sent only when the stream is
+    /// not ready.
+    LiveFirst,
+}
+
+/// Handle to gracefully stop [SendAllUnorderedFs](struct.SendAllUnorderedFs.html).
+///
+/// After `shutdown` is called the future stops pulling items from the stream, writes items kept in
+/// memory to the dir, closes the current file and resolves with the sink and the stream. Items
+/// stored on disk are not sent to the sink, they will be read on the next start.
+pub struct ShutdownHandle {
+    tx: oneshot::Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Request shutdown. It does nothing if the future is already resolved.
+    pub fn shutdown(self) {
+        let _ = self.tx.send(());
+    }
+}
+
+/// What to do when storing items in the dir or reading them fails, see
+/// [StoreErrorHandler](trait.StoreErrorHandler.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorePolicy {
+    /// Poll the dir again after the delay.
+    WaitRetry(Duration),
+    /// Continue with the next item. Broken item read from the dir is skipped, item that failed to
+    /// be written is lost.
+    Skip,
+    /// Move the file that can't be read to `quarantine` dir inside the dir and continue with the
+    /// next file. It works like `Skip` for other errors or if the file is still written.
+    Quarantine,
+    /// Resolve the future with the error.
+    Fail,
+}
+
+/// Decides what to do with errors of the dir, like `ErrorHandler` from futures-retry.
+///
+/// It's implemented for closures `FnMut(attempt, &Error) -> StorePolicy`. `attempt` is number of
+/// errors in a row, starting from 1.
+///
+/// # Warning
+/// Returning `Skip` or `Quarantine` for an error that happens again immediately makes the future
+/// busy. Use `attempt` to give up.
+pub trait StoreErrorHandler: Send {
+    fn handle(&mut self, attempt: usize, err: &Error) -> StorePolicy;
+}
+
+impl<F> StoreErrorHandler for F
+where
+    F: FnMut(usize, &Error) -> StorePolicy + Send,
+{
+    fn handle(&mut self, attempt: usize, err: &Error) -> StorePolicy {
+        self(attempt, err)
+    }
 }
 
 custom_error! { pub SendAllFsErr<T>
@@ -299,142 +754,582 @@ custom_error! { pub SendAllFsErr<T>
     Custom{ inner: T } = "Custom error occured",
 }
 
-fn from_custom_err<T>(oth: T) -> SendAllFsErr<T> {
-    SendAllFsErr::Custom { inner: oth }
+/// Error of [SendAllUnorderedFs](struct.SendAllUnorderedFs.html) with the sink and the stream, so
+/// they can be used again.
+pub struct SendAllFsFailure<T: Sink, U> {
+    pub error: SendAllFsErr<T::SinkError>,
+    pub sink: T,
+    pub stream: U,
+}
+
+impl<T: Sink, U> std::fmt::Debug for SendAllFsFailure<T, U>
+where
+    T::SinkError: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SendAllFsFailure")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<T: Sink, U> std::fmt::Display for SendAllFsFailure<T, U>
+where
+    T::SinkError: std::fmt::Debug + std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
 }
 
-enum State {
-    PollComplateRequired,
-    Sended,
+impl<T: Sink, U> std::error::Error for SendAllFsFailure<T, U>
+where
+    T::SinkError: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
 }
 
-#[derive(PartialEq, Eq)]
+fn from_custom_err<T>(oth: T) -> SendAllFsErr<T> {
+    SendAllFsErr::Custom { inner: oth }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Closing {
     Working,
     DirSender,
     ReadingFs,
+    // items failed after the dir was closed are written to a new file.
+    SealFailed,
     Sink,
+    ShutdownSpill,
+    ShutdownSeal,
     Return,
 }
 
-impl<T, U> SendAllUnorderedFs<T, U>
+impl<T, U, S, R> SendAllUnorderedFs<T, U, S, R>
 where
     T: Sink,
     U: Stream<Item = T::SinkItem>,
     T::SinkError: From<U::Error>,
     T::SinkItem: Serialize,
     for<'de> T::SinkItem: Serialize + Deserialize<'de>,
+    S: SpillSender<T::SinkItem>,
+    R: SpillReciver<T::SinkItem>,
 {
+    /// Set the order in which items from the dir and from the stream are sent. See
+    /// [DrainPolicy](enum.DrainPolicy.html).
+    pub fn drain_policy(mut self, policy: DrainPolicy) -> Self {
+        self.drain_policy = policy;
+        self
+    }
+
+    /// Limit how fast items from the dir are sent to the sink. See
+    /// [RateLimit](struct.RateLimit.html).
+    pub fn replay_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(TokenBucket::new(limit));
+        self
+    }
+
+    /// Decide what to do with errors of the dir instead of failing. See
+    /// [StoreErrorHandler](trait.StoreErrorHandler.html).
+    pub fn store_error_handler<H>(mut self, handler: H) -> Self
+    where
+        H: StoreErrorHandler + 'static,
+    {
+        self.error_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Treat sink errors for which `is_transient` returns true like `AsyncSink::NotReady`. Item
+    /// that failed is written to the dir and the sink is not used for a delay given by `backoff`.
+    /// Other errors resolve the future.
+    ///
+    /// # Performance
+    /// Every item is cloned before it's sent to the sink, because the sink drops it on error and
+    /// there is no other way to write it to the dir. The copy is dropped when the sink accepts the
+    /// item. If cloning is expensive, make the sink return the item in its errors, e.g. with
+    /// [RetrySink](struct.RetrySink.html), and use `spill_failed_items` instead, it doesn't clone.
+    ///
+    /// # Notes
+    /// Items already accepted by the sink are lost if its `poll_complete` fails.
+    pub fn spill_on_sink_error<F>(mut self, is_transient: F, backoff: Backoff) -> Self
+    where
+        F: FnMut(&T::SinkError) -> bool + Send + 'static,
+        T::SinkItem: Clone,
+    {
+        self.sink_errors = Some(SinkErrors {
+            is_transient: Box::new(is_transient),
+            clone_item: T::SinkItem::clone,
+            backoff,
+            attempt: 0,
+            delay: None,
+        });
+        self
+    }
+
+    /// Write items returned in sink errors to the dir instead of failing. `take_item` returns the
+    /// item or gives the error back if it doesn't contain one, e.g. `RetryError::take_exhausted`.
+    ///
+    /// # Notes
+    /// Item failed after the dir was closed is written to a new file, it's read on the next start.
+    pub fn spill_failed_items(mut self, take_item: TakeFailed<T>) -> Self {
+        self.take_failed = Some(take_item);
+        self
+    }
+
+    /// Write items from the stream straight to the dir while the sink keeps failing. See
+    /// [CircuitBreaker](struct.CircuitBreaker.html).
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(Breaker::new(breaker));
+        self
+    }
+
+    /// On shutdown write items `take_pending` takes from the sink to the dir, e.g. incomplete
+    /// batch of [BatchSink](struct.BatchSink.html).
+    pub(crate) fn spill_pending(mut self, take_pending: TakePending<T>) -> Self {
+        self.take_pending = Some(take_pending);
+        self
+    }
+
+    /// Send items read from the dir with `send_sized`, it gets their serialized size known from
+    /// the dir, e.g. [BatchSink](struct.BatchSink.html) doesn't serialize them again.
+    pub(crate) fn replay_sized(mut self, send_sized: SendSized<T>) -> Self {
+        self.send_sized = Some(send_sized);
+        self
+    }
+
+    /// Create a handle that can be used to gracefully stop this future.
+    ///
+    /// # Notes
+    /// Only the last created handle is active. Calling it again makes previous handles no-op.
+    pub fn shutdown_handle(&mut self) -> ShutdownHandle {
+        let (tx, rx) = oneshot::channel();
+        self.shutdown = Some(rx);
+        ShutdownHandle { tx }
+    }
+
     fn sink_mut(&mut self) -> &mut T {
         self.sink
             .as_mut()
-            .take()
             .expect("Attempted to poll SendAllUnorderedFs after completion")
     }
 
-    fn stream_mut(&mut self) -> &mut Fuse<U> {
-        self.stream
-            .as_mut()
-            .take()
-            .expect("Attempted to poll SendAllUnorderedFs after completion")
+    /// Return true if the sink is not used because of a transient error.
+    fn sink_backing_off(&mut self) -> Result<bool, SendAllFsErr<T::SinkError>> {
+        let errors = match self.sink_errors.as_mut() {
+            Some(errors) => errors,
+            None => return Ok(false),
+        };
+        if let Some(delay) = errors.delay.as_mut() {
+            if delay.poll().map_err(Error::from)?.is_not_ready() {
+                return Ok(true);
+            }
+            errors.delay = None;
+        }
+        Ok(false)
     }
 
-    fn try_send_to_sink(&mut self, item: T::SinkItem) -> Poll<(), SendAllFsErr<T::SinkError>> {
-        debug_assert!(self.buffered.is_none());
-        if let AsyncSink::NotReady(item) =
-            self.sink_mut().start_send(item).map_err(from_custom_err)?
-        {
-            self.buffered = Some(item);
-            return Ok(Async::NotReady);
+    /// Return `Ok` if `err` is transient or contains failed item. Sink won't be used until backoff
+    /// delay passes after transient error. Returns true if item was taken from the error.
+    fn sink_error(&mut self, err: T::SinkError) -> Result<bool, SendAllFsErr<T::SinkError>> {
+        let err = match self.take_failed {
+            Some(take_item) => match take_item(err) {
+                Ok(item) => {
+                    warn!("Sink failed to send item, writing it to the dir");
+                    self.failed.push_back(item);
+                    return Ok(true);
+                }
+                Err(err) => err,
+            },
+            None => err,
+        };
+        let err = match self.dead_letters.as_mut() {
+            Some((dead_letters, take_rejected)) => match take_rejected(err) {
+                Ok((item, error)) => {
+                    warn!("Sink rejected item: {}, storing it as dead letter", error);
+                    dead_letters.push(item, error);
+                    return Ok(true);
+                }
+                Err(err) => err,
+            },
+            None => err,
+        };
+        let errors = match self.sink_errors.as_mut() {
+            Some(errors) => errors,
+            None => return Err(from_custom_err(err)),
+        };
+        if !(errors.is_transient)(&err) {
+            return Err(from_custom_err(err));
         }
-        Ok(Async::Ready(()))
+        errors.attempt += 1;
+        let delay = errors.backoff.delay(errors.attempt);
+        warn!(
+            "Sink failed {} times in a row, retrying in {:?}",
+            errors.attempt, delay
+        );
+        errors.delay = Some(Delay::new(Instant::now() + delay));
+        // register the task in the timer.
+        self.sink_backing_off()?;
+        Ok(false)
     }
 
-    fn try_send_to_sink_or_dir(
+    /// Send `item` to the sink, `size` is known for items read from the dir.
+    fn sink_start_send(
         &mut self,
         item: T::SinkItem,
-    ) -> Poll<(), SendAllFsErr<T::SinkError>> {
-        //TODO this can change order of items.
-        debug_assert!(self.buffered.is_none());
-        if let AsyncSink::NotReady(item) =
-            self.sink_mut().start_send(item).map_err(from_custom_err)?
-        {
-            if let AsyncSink::NotReady(item) = self.dir_sender.start_send(item)? {
-                self.buffered = Some(item);
-                return Ok(Async::NotReady);
+        size: Option<u64>,
+    ) -> StartSend<T::SinkItem, SendAllFsErr<T::SinkError>> {
+        if self.sink_backing_off()? {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        let copy = self
+            .sink_errors
+            .as_ref()
+            .map(|errors| (errors.clone_item)(&item));
+        let start_send = match (self.send_sized, size) {
+            (Some(send_sized), Some(size)) => send_sized(self.sink_mut(), item, size),
+            _ => self.sink_mut().start_send(item),
+        };
+        match start_send {
+            Ok(AsyncSink::Ready) => {
+                if let Some(errors) = self.sink_errors.as_mut() {
+                    errors.attempt = 0;
+                }
+                self.breaker_result(true)?;
+                Ok(AsyncSink::Ready)
+            }
+            Ok(not_ready) => {
+                self.breaker_result(false)?;
+                Ok(not_ready)
+            }
+            Err(err) => {
+                if self.sink_error(err)? {
+                    // item will be written to the dir.
+                    Ok(AsyncSink::Ready)
+                } else {
+                    self.breaker_result(false)?;
+                    Ok(AsyncSink::NotReady(copy.expect("Item is cloned")))
+                }
+            }
+        }
+    }
+
+    /// Count success or failure of the sink in the circuit breaker.
+    fn breaker_result(&mut self, success: bool) -> Result<(), Error> {
+        if let Some(breaker) = self.breaker.as_mut() {
+            if success {
+                breaker.success();
             } else {
-                trace!("try_send_to_sink_or_dir -> item addted to dir!");
-                self.check_fs_required = true;
+                breaker.failure();
+                // register the task in the timer.
+                breaker.poll_probe()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ready when items read from the dir can be sent to the sink.
+    fn poll_breaker(&mut self) -> Poll<(), Error> {
+        match self.breaker.as_mut() {
+            Some(breaker) => Ok(breaker.poll_probe()?),
+            None => Ok(Async::Ready(())),
+        }
+    }
+
+    /// Return true if items from the stream go straight to the dir.
+    fn breaker_tripped(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_some_and(|breaker| !breaker.is_closed())
+    }
+
+    fn sink_poll_complete(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        loop {
+            if self.sink_backing_off()? {
+                return Ok(Async::NotReady);
+            }
+            match self.sink_mut().poll_complete() {
+                Ok(async_res) => return Ok(async_res),
+                Err(err) => {
+                    if !self.sink_error(err)? {
+                        return Ok(Async::NotReady);
+                    }
+                }
             }
-        } else {
-            trace!("try_send_to_sink_or_dir -> item addted to sink!");
         }
-        Ok(Async::Ready(()))
     }
 
-    fn try_get_item_stream(&mut self) -> Poll<Option<T::SinkItem>, SendAllFsErr<T::SinkError>> {
-        if let Some(item) = self.buffered.take() {
-            return Ok(Async::Ready(Some(item)));
+    fn sink_close(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        loop {
+            if self.sink_backing_off()? {
+                return Ok(Async::NotReady);
+            }
+            match self.sink_mut().close() {
+                Ok(async_res) => return Ok(async_res),
+                Err(err) => {
+                    if !self.sink_error(err)? {
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+        }
+    }
+
+    fn stream_mut(&mut self) -> &mut Fuse<U> {
+        self.stream
+            .as_mut()
+            .expect("Attempted to poll SendAllUnorderedFs after completion")
+    }
+
+    fn poll_shutdown(&mut self) {
+        let requested = match self.shutdown.as_mut().map(|rx| rx.poll()) {
+            None | Some(Ok(Async::NotReady)) => return,
+            Some(Ok(Async::Ready(()))) => true,
+            // Handle is dropped without requesting shutdown.
+            Some(Err(oneshot::Canceled)) => false,
+        };
+        self.shutdown = None;
+
+        if requested {
+            trace!("Shutdown requested");
+            self.stream_closed = match self.stream_closed {
+                Closing::Working => Closing::ShutdownSpill,
+                Closing::DirSender => Closing::ShutdownSeal,
+                Closing::ReadingFs | Closing::Sink if self.take_pending.is_some() => {
+                    Closing::ShutdownSpill
+                }
+                Closing::ReadingFs | Closing::Sink => Closing::Return,
+                Closing::SealFailed => Closing::ShutdownSeal,
+                Closing::ShutdownSpill => Closing::ShutdownSpill,
+                Closing::ShutdownSeal => Closing::ShutdownSeal,
+                Closing::Return => Closing::Return,
+            };
         }
+    }
+
+    /// Tokens item from the stream costs or 0 if items from the stream are not limited. Item is
+    /// serialized to get its size only with `RateUnit::Bytes`.
+    fn live_cost(&self, item: &T::SinkItem) -> Result<u64, Error> {
+        match self.rate_limit.as_ref().map(TokenBucket::limit) {
+            Some(limit) if limit.is_limiting_live() => match limit.unit() {
+                RateUnit::Items => Ok(1),
+                RateUnit::Bytes => bincode::serialized_size(item).map_err(Error::encode),
+            },
+            _ => Ok(0),
+        }
+    }
 
-        self.stream_mut()
-            .poll()
-            .map_err(T::SinkError::from)
-            .map_err(from_custom_err)
+    /// Tokens the last item read from the dir costs or 0 if there is no rate limit. Size of the
+    /// item is known from the dir, so it isn't serialized again.
+    fn replay_cost(&self) -> u64 {
+        match self.rate_limit.as_ref().map(|bucket| bucket.limit().unit()) {
+            None => 0,
+            Some(RateUnit::Items) => 1,
+            Some(RateUnit::Bytes) => self.dir_reciver.item_size(),
+        }
     }
 
-    fn try_get_item_fs(&mut self) -> Poll<Option<T::SinkItem>, SendAllFsErr<T::SinkError>> {
-        if let Some(item) = self.buffered.take() {
-            return Ok(Async::Ready(Some(item)));
+    fn consume_tokens(&mut self, cost: u64) {
+        if let Some(bucket) = self.rate_limit.as_mut() {
+            bucket.consume(cost);
         }
+    }
+
+    /// Wait until `cost` tokens are available.
+    fn poll_tokens(&mut self, cost: u64) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        let bucket = match self.rate_limit.as_mut() {
+            Some(bucket) => bucket,
+            None => return Ok(Async::Ready(())),
+        };
 
-        self.dir_reciver.poll().map_err(SendAllFsErr::from)
+        loop {
+            match bucket.check(cost) {
+                Ok(()) => {
+                    self.replay_delay = None;
+                    return Ok(Async::Ready(()));
+                }
+                Err(at) => {
+                    trace!("Replay is rate limited");
+                    let delay = self.replay_delay.get_or_insert_with(|| Delay::new(at));
+                    delay.reset(at);
+                    try_ready!(delay.poll().map_err(Error::from));
+                }
+            }
+        }
     }
 
-    fn try_get_item(&mut self) -> Poll<Option<T::SinkItem>, SendAllFsErr<T::SinkError>> {
-        if let Some(item) = self.buffered.take() {
-            return Ok(Async::Ready(Some(item)));
+    /// Return true if live item can't be send to the sink because of the rate limit.
+    fn live_over_limit(&mut self, cost: u64) -> bool {
+        match self.rate_limit.as_mut() {
+            Some(bucket) if bucket.limit().is_limiting_live() => bucket.check(cost).is_err(),
+            _ => false,
         }
+    }
 
-        match self.dir_reciver.poll()? {
-            Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
-            Async::Ready(None) => (), // dir is close but stream can be still open.
-            Async::NotReady => (),
+    /// Return true if replayed item waits for the sink, not for the rate limit or the circuit
+    /// breaker.
+    fn replay_blocked_by_sink(&self) -> bool {
+        self.replayed.is_some()
+            && self.replay_delay.is_none()
+            && !self.breaker.as_ref().is_some_and(Breaker::is_open)
+    }
+
+    fn try_send_to_sink_or_dir(
+        &mut self,
+        item: T::SinkItem,
+    ) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        //TODO this can change order of items.
+        debug_assert!(self.buffered.is_none());
+        let cost = self.live_cost(&item)?;
+        let item = if self.drain_policy == DrainPolicy::BacklogFirst && self.check_fs_required {
+            // Item has to wait in the dir for older items.
+            item
+        } else if self.live_over_limit(cost) || self.breaker_tripped() {
+            item
+        } else {
+            match self.sink_start_send(item, None)? {
+                AsyncSink::NotReady(item) => item,
+                AsyncSink::Ready => {
+                    trace!("try_send_to_sink_or_dir -> item addted to sink!");
+                    self.consume_tokens(cost);
+                    return Ok(Async::Ready(()));
+                }
+            }
         };
 
-        self.stream_mut()
-            .poll()
-            .map_err(T::SinkError::from)
-            .map_err(from_custom_err)
+        if let AsyncSink::NotReady(item) = self.dir_sender.start_send(item)? {
+            self.buffered = Some(item);
+            return Ok(Async::NotReady);
+        }
+        trace!("try_send_to_sink_or_dir -> item addted to dir!");
+        self.check_fs_required = true;
+        self.dir_flushed = false;
+        Ok(Async::Ready(()))
     }
 
-    /// Send all items from stream to fs_storage. It should be called only when fs_storage is not
-    /// empty.
-    fn fill_fs_sink(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+    /// Send items read from the dir to the sink. Returns `Ready` when the dir is closed.
+    fn replay_fs(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
         loop {
-            if let Some(item) = try_ready!(self.try_get_item_stream()) {
-                if let AsyncSink::NotReady(item) = self.dir_sender.start_send(item)? {
-                    trace!("\t \t fill_fs_sink -> NotReady");
-                    self.buffered = Some(item);
-                    return Ok(Async::NotReady);
+            let item = match self.replayed.take() {
+                Some(item) => item,
+                None => {
+                    // Items not flushed yet can't be seen by the reciver.
+                    let dir_flushed = self.dir_flushed;
+                    if self.dir_ended && !self.check_fs_required {
+                        return Ok(Async::Ready(()));
+                    }
+                    let poll_dir = self.dir_reciver.poll();
+                    self.read_failed = poll_dir.is_err();
+                    let poll_dir = poll_dir?;
+                    self.dir_ended = matches!(poll_dir, Async::Ready(None));
+                    match poll_dir {
+                        Async::Ready(Some(item)) => item,
+                        // the last file is sealed, sender creates the next one with the next item.
+                        Async::Ready(None) => {
+                            self.check_fs_required = false;
+                            return Ok(Async::Ready(()));
+                        }
+                        Async::NotReady => {
+                            if dir_flushed && self.dir_reciver.is_caught_up() {
+                                self.check_fs_required = false;
+                            }
+                            return Ok(Async::NotReady);
+                        }
+                    }
                 }
+            };
+
+            let cost = self.replay_cost();
+            if self.poll_tokens(cost)?.is_not_ready() || self.poll_breaker()?.is_not_ready() {
+                self.replayed = Some(item);
+                return Ok(Async::NotReady);
+            }
+
+            let size = self.dir_reciver.item_size();
+            if let AsyncSink::NotReady(item) = self.sink_start_send(item, Some(size))? {
+                self.replayed = Some(item);
+                return Ok(Async::NotReady);
             }
+            self.consume_tokens(cost);
         }
     }
 
-    fn read_fs_and_fill_sink(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+    /// Send items from the stream to the sink or to the dir if sink is not ready. Returns `Ready`
+    /// when the stream is finished.
+    fn forward_stream(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
         loop {
-            //FIXME this probably can be infinite loop in cerain situation;
-            match try_ready!(self.try_get_item_fs()) {
-                Some(item) => try_ready!(self.try_send_to_sink(item)),
-                None => return Ok(Async::Ready(())),
+            let item = match self.buffered.take() {
+                Some(item) => item,
+                None => {
+                    let opt_item = try_ready!(self
+                        .stream_mut()
+                        .poll()
+                        .map_err(T::SinkError::from)
+                        .map_err(from_custom_err));
+                    match opt_item {
+                        Some(item) => item,
+                        None => return Ok(Async::Ready(())),
+                    }
+                }
             };
+
+            try_ready!(self.try_send_to_sink_or_dir(item));
+        }
+    }
+
+    fn read_fs_and_fill_sink(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        loop {
+            if self.replay_fs()?.is_ready() {
+                return Ok(Async::Ready(()));
+            }
+
+            try_ready!(self.sink_poll_complete());
+            if !self.replay_blocked_by_sink() {
+                // waiting for the dir.
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    /// Write item kept in memory to the dir.
+    fn spill_buffered(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        while let Some(item) = self.buffered.take() {
+            if let AsyncSink::NotReady(item) = self.dir_sender.start_send_unbounded(item)? {
+                self.buffered = Some(item);
+                try_ready!(self.dir_sender.poll_complete());
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Write items taken from sink errors to the dir.
+    fn spill_failed(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        while let Some(item) = self.failed.pop_front() {
+            if let AsyncSink::NotReady(item) = self.dir_sender.start_send_unbounded(item)? {
+                self.failed.push_front(item);
+                try_ready!(self.dir_sender.poll_complete());
+                continue;
+            }
+            self.check_fs_required = true;
+            self.dir_flushed = false;
         }
+        Ok(Async::Ready(()))
     }
 
     fn try_sink_or_dir_poll_complete(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
-        let sink_res = self.sink_mut().poll_complete().map_err(from_custom_err)?;
+        let sink_res = self.sink_poll_complete()?;
         let dir_res = self.dir_sender.poll_complete()?;
+        if let Some((dead_letters, _)) = self.dead_letters.as_mut() {
+            try_ready!(dead_letters.poll_complete());
+        }
+        if dir_res.is_ready() {
+            self.dir_flushed = true;
+            let evict = self.dir_reciver.evict();
+            self.read_failed = evict.is_err();
+            evict?;
+        }
         if sink_res.is_ready() && dir_res.is_ready() {
             Ok(Async::Ready(()))
         } else {
@@ -442,6 +1337,51 @@ where
         }
     }
 
+    /// Decide what to do with store error. Returns error if the future should fail.
+    fn handle_store_error(&mut self, err: Error) -> Result<(), SendAllFsErr<T::SinkError>> {
+        self.error_attempt += 1;
+        let policy = match self.error_handler.as_mut() {
+            Some(handler) => handler.handle(self.error_attempt, &err),
+            None => StorePolicy::Fail,
+        };
+
+        match policy {
+            StorePolicy::WaitRetry(delay) => {
+                warn!("Retrying in {:?} after error: {}", delay, err);
+                self.store_retry = Some(Delay::new(Instant::now() + delay));
+            }
+            StorePolicy::Skip => warn!("Skipping after error: {}", err),
+            StorePolicy::Quarantine => {
+                warn!("Quarantine after error: {}", err);
+                if self.read_failed && !self.dir_reciver.quarantine_file()? {
+                    warn!("File can't be moved to quarantine, skipping broken item");
+                }
+            }
+            StorePolicy::Fail => return Err(err.into()),
+        }
+        Ok(())
+    }
+
+    fn poll_store_retry(&mut self) -> Poll<(), SendAllFsErr<T::SinkError>> {
+        if let Some(delay) = self.store_retry.as_mut() {
+            try_ready!(delay.poll().map_err(Error::from));
+            self.store_retry = None;
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn fail(&mut self, error: SendAllFsErr<T::SinkError>) -> SendAllFsFailure<T, U> {
+        SendAllFsFailure {
+            error,
+            sink: self.sink.take().expect("Calling after resolve is error!"),
+            stream: self
+                .stream
+                .take()
+                .expect("Calling after resolve is error!")
+                .into_inner(),
+        }
+    }
+
     fn take_result(&mut self) -> Poll<(T, U), SendAllFsErr<T::SinkError>> {
         Ok(Async::Ready((
             self.sink.take().expect("Calling after resolve is error!"),
@@ -453,19 +1393,115 @@ where
     }
 }
 
-impl<T, U> Future for SendAllUnorderedFs<T, U>
+impl<T, U> SendAllUnorderedFs<T, U>
+where
+    T: Sink,
+    U: Stream<Item = T::SinkItem>,
+    T::SinkError: From<U::Error>,
+    for<'de> T::SinkItem: Serialize + Deserialize<'de>,
+{
+    /// Skip items from the dir older than `ttl`. Files with all items expired are removed without
+    /// reading them.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.dir_reciver.set_ttl(ttl);
+        self
+    }
+
+    /// Store items rejected by the sink in `DEAD_LETTER_DIR` inside the dir instead of failing.
+    /// `take_rejected` returns the item with error message or gives the error back if it isn't a
+    /// rejection, e.g. `RetryError::take_rejected`. See
+    /// [reinject_dead_letters](fn.reinject_dead_letters.html) to send them again.
+    ///
+    /// # Errors
+    /// Returns `InvalidInput` if segments are not stored on local fs.
+    pub fn dead_letters(mut self, take_rejected: TakeRejected<T>) -> io::Result<Self> {
+        let dir_path = self.dir_sender.dir_path().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Dead letters need segments stored on local fs",
+            )
+        })?;
+        let dead_letters = DeadLetters::new(dir_path)?;
+        self.dead_letters = Some((dead_letters, take_rejected));
+        Ok(self)
+    }
+
+    /// Read and write files in the dir with `backend` instead of `ThreadPool`, e.g. `IoUring`.
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> io::Result<Self> {
+        let backend: Arc<dyn Backend> = Arc::new(backend);
+        self.dir_reciver.set_backend(backend.clone())?;
+        self.dir_sender = self.dir_sender.backend(backend)?;
+        Ok(self)
+    }
+
+    /// Reserve `len` bytes on disk for every file in the dir, so missing space is reported when a
+    /// file is created. See `RawDirSender::preallocate`.
+    pub fn preallocate_files(mut self, len: u64) -> io::Result<Self> {
+        self.dir_sender = self.dir_sender.preallocate(len)?;
+        Ok(self)
+    }
+}
+
+impl<T, U, S, R> Future for SendAllUnorderedFs<T, U, S, R>
 where
     T: Sink,
     U: Stream<Item = T::SinkItem>,
     T::SinkError: From<U::Error>,
     T::SinkItem: Serialize + DeserializeOwned,
+    S: SpillSender<T::SinkItem>,
+    R: SpillReciver<T::SinkItem>,
 {
     type Item = (T, U);
-    type Error = SendAllFsErr<T::SinkError>;
+    type Error = SendAllFsFailure<T, U>;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_shutdown();
+
+        loop {
+            let result = match self.poll_store_retry() {
+                Ok(Async::Ready(())) => self.poll_sending(),
+                other => other.map(|_| Async::NotReady),
+            };
+            match result {
+                Ok(async_item) => {
+                    if self.store_retry.is_none() {
+                        self.error_attempt = 0;
+                    }
+                    return Ok(async_item);
+                }
+                Err(SendAllFsErr::StoreError { source }) => {
+                    if let Err(err) = self.handle_store_error(source) {
+                        return Err(self.fail(err));
+                    }
+                }
+                Err(err) => return Err(self.fail(err)),
+            }
+        }
+    }
+}
+
+impl<T, U, S, R> SendAllUnorderedFs<T, U, S, R>
+where
+    T: Sink,
+    U: Stream<Item = T::SinkItem>,
+    T::SinkError: From<U::Error>,
+    T::SinkItem: Serialize + DeserializeOwned,
+    S: SpillSender<T::SinkItem>,
+    R: SpillReciver<T::SinkItem>,
+{
+    fn poll_sending(&mut self) -> Poll<(T, U), SendAllFsErr<T::SinkError>> {
         loop {
             trace!("SendAllUnorderedFs -> poll");
+            if !self.failed.is_empty() {
+                try_ready!(self.spill_failed());
+                // the dir was closed, close the new file too. Items are not replayed again, they
+                // could fail forever. They are read on the next start.
+                self.stream_closed = match self.stream_closed {
+                    Closing::ReadingFs | Closing::Sink => Closing::SealFailed,
+                    Closing::Return => Closing::ShutdownSeal,
+                    other => other,
+                };
+            }
             match self.stream_closed {
                 Closing::Working => (),
                 Closing::DirSender => {
@@ -478,12 +1514,36 @@ where
                     try_ready!(self.read_fs_and_fill_sink());
                     self.stream_closed = Closing::Sink;
                 }
+                Closing::SealFailed => {
+                    trace!("Closing dir sender with failed items");
+                    try_ready!(self.dir_sender.close());
+                    self.stream_closed = Closing::Sink;
+                }
                 Closing::Sink => {
                     trace!("Poll complet for sink through close is called()");
-                    try_ready!(self.sink_mut().close().map_err(from_custom_err));
+                    try_ready!(self.sink_close());
+                    self.stream_closed = Closing::Return;
+                }
+                Closing::ShutdownSpill => {
+                    trace!("Shutdown -> writing buffered item to dir");
+                    try_ready!(self.spill_buffered());
+                    if let (Some(take_pending), Some(sink)) =
+                        (self.take_pending, self.sink.as_mut())
+                    {
+                        self.failed.extend(take_pending(sink));
+                    }
+                    try_ready!(self.spill_failed());
+                    self.stream_closed = Closing::ShutdownSeal;
+                }
+                Closing::ShutdownSeal => {
+                    trace!("Shutdown -> closing dir sender");
+                    try_ready!(self.dir_sender.close());
                     self.stream_closed = Closing::Return;
                 }
                 Closing::Return => {
+                    if let Some((dead_letters, _)) = self.dead_letters.as_mut() {
+                        try_ready!(dead_letters.close());
+                    }
                     return self.take_result();
                 }
             }
@@ -492,15 +1552,35 @@ where
                 continue;
             }
 
-            match self.try_get_item()? {
-                Async::Ready(Some(item)) => {
-                    try_ready!(self.try_send_to_sink_or_dir(item));
+            // The dir is closed only when sender rotates files faster than they are read, the
+            // stream can be still open. That's why result of replay_fs is ignored.
+            let stream_res = match self.drain_policy {
+                DrainPolicy::BacklogFirst | DrainPolicy::Interleave => {
+                    let _ = self.replay_fs()?;
+                    self.forward_stream()?
                 }
-                Async::Ready(None) => self.stream_closed = Closing::DirSender,
-                Async::NotReady => {
-                    trace!("Stream is not ready!");
-                    try_ready!(self.try_sink_or_dir_poll_complete());
+                DrainPolicy::LiveFirst => {
+                    let stream_res = self.forward_stream()?;
+                    if stream_res.is_not_ready() {
+                        // stream is idle or its item waits for a lane with exhausted quota
+                        let _ = self.replay_fs()?;
+                    }
+                    stream_res
                 }
+            };
+
+            if stream_res.is_ready() {
+                self.stream_closed = Closing::DirSender;
+                continue;
+            }
+
+            trace!("Stream is not ready!");
+            try_ready!(self.try_sink_or_dir_poll_complete());
+            // Buffered item is not accepted by the dir only when quota of a lane is exhausted, it
+            // waits until items are read from the lane.
+            if self.failed.is_empty() && !self.replay_blocked_by_sink() {
+                // Nothing more to do until stream or dir is ready.
+                return Ok(Async::NotReady);
             }
         }
     }
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// Write buffering of a file sender, see `UnboundedFileSender::group_commit` and
+/// `DirSender::group_commit`.
+///
+/// Items are collected in memory and written to the file with one write when they take at least
+/// `max_bytes` or `linger` passed since the first of them. With `fsync` the file is synced after
+/// every write, so items written together share one sync.
+#[derive(Debug, Clone)]
+pub struct GroupCommit {
+    pub(crate) max_bytes: usize,
+    pub(crate) linger: Duration,
+    pub(crate) fsync: bool,
+}
+
+impl GroupCommit {
+    /// Write items when they take at least `max_bytes`, `start_send` is not ready until they are
+    /// written.
+    pub fn new(max_bytes: usize) -> Self {
+        GroupCommit {
+            max_bytes: max_bytes.max(1),
+            linger: Duration::from_secs(0),
+            fsync: false,
+        }
+    }
+
+    /// Wait up to `linger` for more items before `poll_complete` writes them.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Sync data of the file after every write.
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+}
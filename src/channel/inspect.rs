@@ -0,0 +1,190 @@
+use super::error::Error;
+use super::frame::{self, FrameReader};
+use super::lock::DirLock;
+use super::segment;
+use futures::Async;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncRead;
+
+/// Description of a single file stored in a dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Index of the file in the dir.
+    pub index: usize,
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Number of complete items in the file.
+    pub items: u64,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+    /// True if sender will never append to the file.
+    pub sealed: bool,
+}
+
+/// Describe all files stored in `dir_path` without modifying them.
+pub fn list_segments(dir_path: &Path) -> io::Result<Vec<SegmentInfo>> {
+    segment::file_indexes(dir_path)?
+        .into_iter()
+        .map(|index| {
+            let path = segment::file_path(dir_path, index);
+            let (items, _offset) = segment::seek_item(&path, u64::MAX)?;
+            Ok(SegmentInfo {
+                index,
+                items,
+                bytes: std::fs::metadata(&path)?.len(),
+                sealed: segment::is_sealed(&path)?,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Item read from a file without decoding it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawRecord {
+    /// Offset of the item in the file, counted from the end of the header.
+    pub offset: u64,
+    /// Time when item was written, in milliseconds since UNIX epoch.
+    pub written_at: u64,
+    /// Item serialized with bincode.
+    pub payload: Vec<u8>,
+}
+
+/// Iterator over items stored in a file, see `raw_records`.
+pub struct RawRecords {
+    reader: FrameReader<BlockingRead>,
+    path: PathBuf,
+    broken: bool,
+}
+
+// File read by `FrameReader` outside of a task. Reads from a file never return `WouldBlock`.
+struct BlockingRead(io::Take<File>);
+
+impl Read for BlockingRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl AsyncRead for BlockingRead {}
+
+/// Read items stored in file in `path` without decoding them. Incomplete item at the end of the
+/// file is not returned.
+pub fn raw_records(path: &Path) -> io::Result<RawRecords> {
+    let (file, len) = segment::open_items(path)?;
+    Ok(RawRecords {
+        reader: FrameReader::new(BlockingRead(file.take(len)), path, 0).with_end(len),
+        path: path.to_path_buf(),
+        broken: false,
+    })
+}
+
+impl RawRecords {
+    /// Offset after the last item read.
+    fn offset(&self) -> u64 {
+        self.reader.offset()
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<RawRecord>> {
+        let offset = self.reader.offset();
+        let frame = match self.reader.poll_frame()? {
+            Async::Ready(Some(frame)) => frame,
+            Async::Ready(None) => return Ok(None),
+            Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+        match frame::split_frame(frame) {
+            Some((written_at, payload)) => Ok(Some(RawRecord {
+                offset,
+                written_at,
+                payload: payload.to_vec(),
+            })),
+            None => Err(Error::layout_io(
+                &self.path,
+                format!("item at offset {} is too short", offset),
+            )),
+        }
+    }
+}
+
+impl Iterator for RawRecords {
+    type Item = io::Result<RawRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.broken {
+            return None;
+        }
+        match self.read_record() {
+            Ok(opt_record) => opt_record.map(Ok),
+            Err(err) => {
+                // don't read garbage after broken item.
+                self.broken = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Result of `verify_segment`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentReport {
+    /// Number of complete items.
+    pub items: u64,
+    /// Bytes after the last complete item. It's left when program was killed during write.
+    pub trailing_bytes: u64,
+    /// Index entries which don't point to the start of an item.
+    pub bad_index_entries: u64,
+}
+
+impl SegmentReport {
+    /// Return true if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.trailing_bytes == 0 && self.bad_index_entries == 0
+    }
+}
+
+/// Check that items in file in `path` are framed correctly and its index points to them.
+///
+/// # Notes
+/// Files don't store checksums, so content of items is not verified.
+pub fn verify_segment(path: &Path) -> io::Result<SegmentReport> {
+    let mut records = raw_records(path)?;
+    let offsets = records
+        .by_ref()
+        .map(|record| record.map(|record| record.offset))
+        .collect::<io::Result<Vec<_>>>()?;
+    let end = records.offset();
+
+    let bad_index_entries = segment::read_index(path)?
+        .into_iter()
+        .filter(|&(ordinal, offset)| offsets.get(ordinal as usize) != Some(&offset))
+        .count() as u64;
+
+    Ok(SegmentReport {
+        items: offsets.len() as u64,
+        trailing_bytes: std::fs::metadata(path)?.len() - segment::HEADER_LEN - end,
+        bad_index_entries,
+    })
+}
+
+/// Remove sealed files from `dir_path`, or all files if `all` is true. Returns removed files.
+///
+/// # Errors
+/// Returns `WouldBlock` if `all` is true and a sender or reciver uses the dir.
+pub fn purge(dir_path: &Path, all: bool) -> io::Result<Vec<PathBuf>> {
+    let _lock = if all {
+        Some(DirLock::exclusive(dir_path)?)
+    } else {
+        None
+    };
+    let mut removed = Vec::new();
+    for index in segment::file_indexes(dir_path)? {
+        let path = segment::file_path(dir_path, index);
+        if all || segment::is_sealed(&path)? {
+            segment::remove(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,465 @@
+use super::codec::DirReciver;
+use super::codec::DirSender;
+use super::error::Error;
+use super::fs_sender::{new_send_all, SendAllUnorderedFs, SpillReciver, SpillSender};
+use super::inspect::list_segments;
+use futures::prelude::*;
+use futures::task::AtomicTask;
+use futures::try_ready;
+use log::{trace, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What to do with a new item when lane's dir holds `quota` items.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// Don't take new items from the stream until items are read from the lane. Items that can't
+    /// wait, like the item kept in memory on shutdown, are stored over the quota.
+    Block,
+    /// Drop the new item.
+    DropNewest,
+    /// Drop the oldest item stored in the lane and store the new one. Oldest items are skipped
+    /// when the new one is flushed and files with only skipped items are removed, skipped items of
+    /// a file that isn't read to the end yet are read again after restart.
+    DropOldest,
+    /// Fail with `Error::Quota`.
+    Fail,
+}
+
+/// Order in which lanes are drained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaneScheduling {
+    /// Items from a lane are sent only when all lanes before it are empty.
+    Priority,
+    /// Every lane sends up to its `weight` items in turn.
+    Weighted,
+}
+
+/// Configuration of a single lane of [SendAllLanesFs](struct.SendAllLanesFs.html).
+#[derive(Debug, Clone)]
+pub struct Lane {
+    dir_path: PathBuf,
+    max_items_in_file: usize,
+    weight: u32,
+    quota: Option<usize>,
+    eviction: Eviction,
+}
+
+impl Lane {
+    /// Lane that stores items in `dir_path`. Dir has to exist.
+    pub fn new(dir_path: PathBuf) -> Self {
+        Lane {
+            dir_path,
+            max_items_in_file: 1000,
+            weight: 1,
+            quota: None,
+            eviction: Eviction::Block,
+        }
+    }
+
+    /// Max number of items in single file. Default is 1000.
+    pub fn max_items_in_file(mut self, max_items_in_file: usize) -> Self {
+        self.max_items_in_file = max_items_in_file;
+        self
+    }
+
+    /// Number of items sent in one turn when lanes are drained with
+    /// `LaneScheduling::Weighted`. Default is 1.
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight.max(1);
+        self
+    }
+
+    /// Limit number of items stored in the lane. `eviction` decides what happens with items over
+    /// the limit. Items left in the dir by previous run are counted too.
+    pub fn quota(mut self, max_items: usize, eviction: Eviction) -> Self {
+        self.quota = Some(max_items);
+        self.eviction = eviction;
+        self
+    }
+}
+
+// Counters shared by sender and reciver of a lane.
+#[derive(Default)]
+struct LaneState {
+    // items stored in the lane and not read yet.
+    stored: AtomicUsize,
+    // oldest items to drop, see `Eviction::DropOldest`.
+    to_evict: AtomicUsize,
+    // sender blocked by `Eviction::Block`, it's notified when an item is read.
+    blocked: AtomicTask,
+}
+
+impl LaneState {
+    fn is_full(&self, quota: Option<usize>) -> bool {
+        let stored = self.stored.load(Ordering::Acquire);
+        let to_evict = self.to_evict.load(Ordering::Acquire);
+        quota.is_some_and(|quota| stored - to_evict.min(stored) >= quota)
+    }
+
+    fn items_read(&self, n: usize) {
+        let _ = self
+            .stored
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |stored| {
+                Some(stored.saturating_sub(n))
+            });
+        self.blocked.notify();
+    }
+}
+
+struct LaneSender<T> {
+    dir_path: PathBuf,
+    sender: DirSender<T>,
+    quota: Option<usize>,
+    eviction: Eviction,
+    state: Arc<LaneState>,
+}
+
+impl<T: Serialize> LaneSender<T> {
+    /// Store item in the lane respecting quota.
+    fn start_send(&mut self, item: T) -> StartSend<T, Error> {
+        let evict = self.state.is_full(self.quota);
+        if evict {
+            match self.eviction {
+                Eviction::Block => {
+                    self.state.blocked.register();
+                    // reciver could read an item before the task was registered.
+                    if self.state.is_full(self.quota) {
+                        return Ok(AsyncSink::NotReady(item));
+                    }
+                }
+                Eviction::DropNewest => {
+                    warn!("Lane is full, dropping new item");
+                    return Ok(AsyncSink::Ready);
+                }
+                Eviction::DropOldest => (),
+                Eviction::Fail => {
+                    return Err(Error::Quota {
+                        path: self.dir_path.clone(),
+                        max_items: self.quota.unwrap_or_default(),
+                    })
+                }
+            }
+        }
+
+        let async_sink = self.start_send_unbounded(item)?;
+        if async_sink.is_ready() && evict && self.eviction == Eviction::DropOldest {
+            warn!("Lane is full, dropping oldest item");
+            self.state.to_evict.fetch_add(1, Ordering::AcqRel);
+        }
+        Ok(async_sink)
+    }
+
+    fn start_send_unbounded(&mut self, item: T) -> StartSend<T, Error> {
+        let async_sink = self.sender.start_send(item)?;
+        if async_sink.is_ready() {
+            self.state.stored.fetch_add(1, Ordering::AcqRel);
+        }
+        Ok(async_sink)
+    }
+}
+
+struct LaneReciver<T> {
+    reciver: DirReciver<T>,
+    weight: u32,
+    state: Arc<LaneState>,
+    // true if the reciver ended last time, it's polled again for files created after that.
+    closed: bool,
+}
+
+impl<T> LaneReciver<T>
+where
+    T: DeserializeOwned,
+{
+    /// Skip items evicted by the sender without reading them. Items that are not flushed yet are
+    /// skipped later.
+    fn evict(&mut self) -> Result<(), Error> {
+        let to_evict = self.state.to_evict.swap(0, Ordering::AcqRel);
+        if to_evict == 0 {
+            return Ok(());
+        }
+        let skipped = match self.reciver.skip_items(to_evict as u64) {
+            Ok(skipped) => skipped as usize,
+            Err(err) => {
+                self.state.to_evict.fetch_add(to_evict, Ordering::AcqRel);
+                return Err(err);
+            }
+        };
+        self.state
+            .to_evict
+            .fetch_add(to_evict - skipped, Ordering::AcqRel);
+        self.state.items_read(skipped);
+        trace!("Evicted {} oldest items", skipped);
+        Ok(())
+    }
+
+    /// Next item from the lane. `Ready(None)` means lane has no more files now.
+    fn poll_item(&mut self) -> Poll<Option<T>, Error> {
+        self.evict()?;
+        loop {
+            let opt_item = try_ready!(self.reciver.poll());
+            self.closed = opt_item.is_none();
+            if self.closed {
+                return Ok(Async::Ready(None));
+            }
+            self.state.items_read(1);
+            let evict =
+                self.state
+                    .to_evict
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+            if evict.is_err() {
+                return Ok(Async::Ready(opt_item));
+            }
+            trace!("Evicted oldest item");
+        }
+    }
+}
+
+/// Sender of [SendAllLanesFs](type.SendAllLanesFs.html), writes every item to the lane chosen by
+/// `classify`.
+pub struct LanesSender<T, F> {
+    lanes: Vec<LaneSender<T>>,
+    classify: F,
+}
+
+impl<T, F> LanesSender<T, F>
+where
+    F: FnMut(&T) -> usize,
+{
+    fn lane(&mut self, item: &T) -> usize {
+        (self.classify)(item).min(self.lanes.len() - 1)
+    }
+}
+
+impl<T, F> Sink for LanesSender<T, F>
+where
+    T: Serialize,
+    F: FnMut(&T) -> usize,
+{
+    type SinkItem = T;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let idx = self.lane(&item);
+        let async_sink = self.lanes[idx].start_send(item)?;
+        if async_sink.is_ready() {
+            trace!("Item added to lane {}", idx);
+        }
+        Ok(async_sink)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let mut ready = true;
+        for lane in self.lanes.iter_mut() {
+            ready &= lane.sender.poll_complete()?.is_ready();
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        let mut ready = true;
+        for lane in self.lanes.iter_mut() {
+            ready &= lane.sender.close()?.is_ready();
+        }
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T, F> SpillSender<T> for LanesSender<T, F>
+where
+    T: Serialize,
+    F: FnMut(&T) -> usize,
+{
+    fn start_send_unbounded(&mut self, item: T) -> StartSend<T, Error> {
+        let idx = self.lane(&item);
+        self.lanes[idx].start_send_unbounded(item)
+    }
+}
+
+/// Reciver of [SendAllLanesFs](type.SendAllLanesFs.html), reads lanes in order given by
+/// `LaneScheduling`.
+pub struct LanesReciver<T> {
+    lanes: Vec<LaneReciver<T>>,
+    scheduling: LaneScheduling,
+    // lane read last, its errors and item size are reported.
+    current: usize,
+    // Lane which turn it is with `LaneScheduling::Weighted`.
+    turn: usize,
+    sent_in_turn: u32,
+}
+
+impl<T> LanesReciver<T>
+where
+    T: DeserializeOwned,
+{
+    fn poll_lane(&mut self, idx: usize) -> Poll<Option<T>, Error> {
+        self.current = idx;
+        self.lanes[idx].poll_item()
+    }
+
+    /// Items from a lane are read only when all lanes before it have nothing to read.
+    fn poll_priority(&mut self) -> Poll<Option<T>, Error> {
+        for idx in 0..self.lanes.len() {
+            if let Async::Ready(Some(item)) = self.poll_lane(idx)? {
+                return Ok(Async::Ready(Some(item)));
+            }
+        }
+        self.poll_closed()
+    }
+
+    /// Every lane gives up to its `weight` items in turn.
+    fn poll_weighted(&mut self) -> Poll<Option<T>, Error> {
+        // lane which turn it is can be already used up, it's read again after the others.
+        for _ in 0..=self.lanes.len() {
+            let idx = self.turn;
+            if self.sent_in_turn < self.lanes[idx].weight {
+                if let Async::Ready(Some(item)) = self.poll_lane(idx)? {
+                    self.sent_in_turn += 1;
+                    return Ok(Async::Ready(Some(item)));
+                }
+            }
+            self.turn = (self.turn + 1) % self.lanes.len();
+            self.sent_in_turn = 0;
+        }
+        self.poll_closed()
+    }
+
+    fn poll_closed(&self) -> Poll<Option<T>, Error> {
+        if self.lanes.iter().all(|lane| lane.closed) {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T> Stream for LanesReciver<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.scheduling {
+            LaneScheduling::Priority => self.poll_priority(),
+            LaneScheduling::Weighted => self.poll_weighted(),
+        }
+    }
+}
+
+impl<T> SpillReciver<T> for LanesReciver<T>
+where
+    T: DeserializeOwned,
+{
+    fn is_caught_up(&self) -> bool {
+        self.lanes
+            .iter()
+            .all(|lane| lane.closed || lane.reciver.is_caught_up())
+    }
+
+    fn item_size(&self) -> u64 {
+        self.lanes[self.current].reciver.item_size()
+    }
+
+    fn quarantine_file(&mut self) -> Result<bool, Error> {
+        self.lanes[self.current].reciver.quarantine_file()
+    }
+
+    fn evict(&mut self) -> Result<(), Error> {
+        for idx in 0..self.lanes.len() {
+            self.current = idx;
+            self.lanes[idx].evict()?;
+        }
+        Ok(())
+    }
+}
+
+/// Future like [SendAllUnorderedFs](struct.SendAllUnorderedFs.html) that stores items in several
+/// dirs (lanes).
+///
+/// Every item from the stream is classified into a lane. When sink is not ready item is written
+/// to its lane. Items from lanes are sent to the sink in order given by
+/// [LaneScheduling](enum.LaneScheduling.html).
+pub type SendAllLanesFs<T, U, F> = SendAllUnorderedFs<
+    T,
+    U,
+    LanesSender<<T as Sink>::SinkItem, F>,
+    LanesReciver<<T as Sink>::SinkItem>,
+>;
+
+pub fn new_send_all_lanes<T, U, F>(
+    sink: T,
+    stream: U,
+    lanes: Vec<Lane>,
+    scheduling: LaneScheduling,
+    classify: F,
+) -> io::Result<SendAllLanesFs<T, U, F>>
+where
+    T: Sink,
+    U: Stream<Item = T::SinkItem>,
+    T::SinkError: From<U::Error>,
+    T::SinkItem: Serialize + DeserializeOwned,
+    F: FnMut(&T::SinkItem) -> usize,
+{
+    if lanes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "At least one lane is required",
+        ));
+    }
+
+    let mut senders = Vec::with_capacity(lanes.len());
+    let mut recivers = Vec::with_capacity(lanes.len());
+    for lane in lanes {
+        let (sender, reciver) =
+            super::unordered_dir_fs(lane.dir_path.clone(), lane.max_items_in_file)?;
+        // items left by previous run count to the quota.
+        let stored = list_segments(&lane.dir_path)?
+            .iter()
+            .map(|segment| segment.items as usize)
+            .sum();
+        let state = Arc::new(LaneState {
+            stored: AtomicUsize::new(stored),
+            ..LaneState::default()
+        });
+        senders.push(LaneSender {
+            dir_path: lane.dir_path,
+            sender,
+            quota: lane.quota,
+            eviction: lane.eviction,
+            state: state.clone(),
+        });
+        recivers.push(LaneReciver {
+            reciver,
+            weight: lane.weight,
+            state,
+            closed: false,
+        });
+    }
+
+    let sender = LanesSender {
+        lanes: senders,
+        classify,
+    };
+    let reciver = LanesReciver {
+        lanes: recivers,
+        scheduling,
+        current: 0,
+        turn: 0,
+        sent_in_turn: 0,
+    };
+    Ok(new_send_all(sink, stream, sender, reciver))
+}
@@ -0,0 +1,74 @@
+use super::segment;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// Name of the lock file inside a dir.
+pub const LOCK_FILE: &str = ".lock";
+
+/// Advisory lock of a dir, released on drop or when process exits.
+///
+/// Senders and recivers hold a shared lock, offline operations like compaction need an exclusive
+/// one.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock `dir_path` shared with other senders and recivers. If nobody else uses the dir,
+    /// temporary files left when program was killed are removed. Temporary files of files other
+    /// users are creating right now are kept, see `segment::create`.
+    pub fn shared(dir_path: &Path) -> io::Result<Self> {
+        let file = open(dir_path)?;
+        if try_lock(&file, true).is_ok() {
+            segment::remove_temp_files(dir_path)?;
+        }
+        lock(dir_path, file, false)
+    }
+
+    /// Lock `dir_path` exclusively.
+    pub fn exclusive(dir_path: &Path) -> io::Result<Self> {
+        lock(dir_path, open(dir_path)?, true)
+    }
+}
+
+fn open(dir_path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir_path.join(LOCK_FILE))
+}
+
+fn lock(dir_path: &Path, file: File, exclusive: bool) -> io::Result<DirLock> {
+    try_lock(&file, exclusive).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock => io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("Dir {:?} is locked", dir_path),
+        ),
+        _ => err,
+    })?;
+    Ok(DirLock { _file: file })
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File, exclusive: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// TODO lock files on other platforms.
+#[cfg(not(unix))]
+fn try_lock(_file: &File, _exclusive: bool) -> io::Result<()> {
+    Ok(())
+}
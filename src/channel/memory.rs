@@ -0,0 +1,241 @@
+use super::backend::BackendFile;
+use super::segment;
+use super::store::{Appender, SegmentStore, Watch};
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+struct Segment {
+    data: Vec<u8>,
+    sealed: bool,
+    modified: SystemTime,
+    // number of changes, watchers compare it with the last one they saw.
+    changes: u64,
+    watchers: Vec<Task>,
+}
+
+impl Segment {
+    fn changed(&mut self) {
+        self.changes += 1;
+        self.modified = SystemTime::now();
+        for task in self.watchers.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+type Shared = Arc<Mutex<Segment>>;
+
+fn lock(segment: &Shared) -> MutexGuard<'_, Segment> {
+    // segment is consistent after every operation, so poisoning can be ignored.
+    segment.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Segments kept in memory, e.g. to test pipelines without touching disk.
+///
+/// Items are lost with the store. Backend is not used, archive, quarantine and dead letters are
+/// not supported.
+#[derive(Default)]
+pub struct MemoryStore {
+    segments: Mutex<BTreeMap<usize, Shared>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn segments(&self) -> MutexGuard<'_, BTreeMap<usize, Shared>> {
+        self.segments.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn get(&self, index: usize) -> io::Result<Shared> {
+        self.segments().get(&index).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Segment {} doesn't exist", index),
+            )
+        })
+    }
+}
+
+impl SegmentStore for MemoryStore {
+    fn dir_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        PathBuf::from(index.to_string())
+    }
+
+    fn list(&self) -> io::Result<Vec<usize>> {
+        Ok(self.segments().keys().cloned().collect())
+    }
+
+    fn create(&self, index: usize) -> io::Result<()> {
+        self.segments().entry(index).or_insert_with(|| {
+            Arc::new(Mutex::new(Segment {
+                data: Vec::new(),
+                sealed: false,
+                modified: SystemTime::now(),
+                changes: 0,
+                watchers: Vec::new(),
+            }))
+        });
+        Ok(())
+    }
+
+    fn append(&self, index: usize) -> io::Result<Appender> {
+        let shared = self.get(index)?;
+        let (items, bytes) = {
+            let mut segment = lock(&shared);
+            let (items, bytes) = segment::scan_items(&segment.data, u64::MAX);
+            if bytes < segment.data.len() as u64 {
+                segment.data.truncate(bytes as usize);
+                segment.changed();
+            }
+            (items, bytes)
+        };
+        Ok(Appender {
+            file: Box::new(MemoryFile {
+                segment: shared,
+                offset: 0,
+            }),
+            index: Box::new(io::sink()),
+            items,
+            bytes,
+            header: 0,
+        })
+    }
+
+    fn seal(&self, index: usize) -> io::Result<()> {
+        let shared = self.get(index)?;
+        let mut segment = lock(&shared);
+        segment.sealed = true;
+        segment.changed();
+        Ok(())
+    }
+
+    fn is_sealed(&self, index: usize) -> io::Result<bool> {
+        Ok(match self.segments().get(&index) {
+            Some(segment) => lock(segment).sealed,
+            None => false,
+        })
+    }
+
+    fn modified(&self, index: usize) -> io::Result<SystemTime> {
+        Ok(lock(&self.get(index)?).modified)
+    }
+
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn BackendFile>> {
+        Ok(Box::new(MemoryFile {
+            segment: self.get(index)?,
+            offset: offset as usize,
+        }))
+    }
+
+    fn seek(&self, index: usize, n: u64) -> io::Result<(u64, u64)> {
+        Ok(segment::scan_items(&lock(&self.get(index)?).data, n))
+    }
+
+    fn remove(&self, index: usize) -> io::Result<()> {
+        self.get(index)?;
+        self.segments().remove(&index);
+        Ok(())
+    }
+
+    fn watch(&self, index: usize) -> io::Result<Watch> {
+        let segment = self.get(index)?;
+        let changes = lock(&segment).changes;
+        Ok(Box::new(MemoryWatch { segment, changes }))
+    }
+}
+
+/// Segment opened for reading or appending.
+struct MemoryFile {
+    segment: Shared,
+    // where reading continues, writes always append.
+    offset: usize,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let segment = lock(&self.segment);
+        let start = self.offset.min(segment.data.len());
+        let n = buf.len().min(segment.data.len() - start);
+        buf[..n].copy_from_slice(&segment.data[start..start + n]);
+        self.offset = start + n;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut segment = lock(&self.segment);
+        if segment.sealed {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Segment is sealed",
+            ));
+        }
+        segment.data.extend_from_slice(buf);
+        segment.changed();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MemoryFile {}
+
+impl AsyncWrite for MemoryFile {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl BackendFile for MemoryFile {
+    fn poll_is_sealed(&mut self) -> Poll<bool, io::Error> {
+        Ok(Async::Ready(lock(&self.segment).sealed))
+    }
+
+    fn poll_set_len(&mut self, len: u64) -> Poll<(), io::Error> {
+        let mut segment = lock(&self.segment);
+        segment.data.resize(len as usize, 0);
+        segment.changed();
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_sync_data(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+struct MemoryWatch {
+    segment: Shared,
+    changes: u64,
+}
+
+impl Stream for MemoryWatch {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<()>, io::Error> {
+        let mut segment = lock(&self.segment);
+        if segment.changes != self.changes {
+            self.changes = segment.changes;
+            return Ok(Async::Ready(Some(())));
+        }
+        if !segment.watchers.iter().any(Task::will_notify_current) {
+            segment.watchers.push(task::current());
+        }
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,124 @@
+use super::backend::BackendFile;
+use futures::{Async, Poll};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Read only memory map of a whole file.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The map is read only, so it can be shared between threads.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    fn new(file: &File, len: usize) -> io::Result<Mmap> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint, reading works without it.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Sealed file read from its memory map, without a syscall per read.
+///
+/// File can't be truncated while it is mapped, the dir lock prevents compaction to do it.
+pub struct MappedFile {
+    // `None` for empty file, it can't be mapped.
+    map: Option<Mmap>,
+    offset: usize,
+}
+
+impl MappedFile {
+    /// Map sealed `file` and read it from `offset`.
+    pub fn new(file: &File, offset: u64) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        let map = if len == 0 {
+            None
+        } else {
+            Some(Mmap::new(file, len)?)
+        };
+        Ok(MappedFile {
+            map,
+            offset: offset as usize,
+        })
+    }
+}
+
+impl Read for MappedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = match self.map {
+            Some(ref map) => map.as_slice(),
+            None => return Ok(0),
+        };
+        let start = self.offset.min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.offset = start + n;
+        Ok(n)
+    }
+}
+
+impl Write for MappedFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(sealed())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for MappedFile {}
+
+impl AsyncWrite for MappedFile {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl BackendFile for MappedFile {
+    fn poll_is_sealed(&mut self) -> Poll<bool, io::Error> {
+        Ok(Async::Ready(true))
+    }
+
+    fn poll_set_len(&mut self, _len: u64) -> Poll<(), io::Error> {
+        Err(sealed())
+    }
+
+    fn poll_sync_data(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn sealed() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Mapped file is sealed")
+}
@@ -2,13 +2,38 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+mod archive;
+mod backend;
+mod backoff;
+mod batch;
+mod breaker;
+mod codec;
+mod compact;
+mod dead_letter;
 mod error;
+mod frame;
 mod fs_receiver;
 mod fs_sender;
+mod group_commit;
+mod inspect;
+mod lanes;
+mod lock;
+mod memory;
+#[cfg(all(unix, feature = "mmap"))]
+mod mmap;
+mod rate_limit;
+mod record;
+mod retry;
+mod segment;
+mod store;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
-use fs_receiver::{DirReciver, FileReciver};
-use fs_sender::{DirSender, UnboundedFileSender};
+use codec::{DirReciver, DirSender, FileReciver, UnboundedFileSender};
+use fs_receiver::RawDirReciver;
+use fs_sender::RawDirSender;
 
 /// Create a pair of UnboundedFileSender and FileReciver.
 ///
@@ -24,8 +49,8 @@ where
     T: Serialize + DeserializeOwned,
 {
     Ok((
-        fs_sender::unbounded::<T>(&path)?,
-        fs_receiver::new::<T>(path)?,
+        codec::unbounded::<T>(&path)?,
+        codec::new_file_reciver::<T>(path)?,
     ))
 }
 
@@ -37,13 +62,78 @@ pub fn unordered_dir_fs<T>(
 where
     T: Serialize + DeserializeOwned,
 {
-    let dir_sender = fs_sender::new_dir_sender(dir_path.clone(), max_items_in_file)?;
-    let dir_reciver = fs_receiver::new_dir_reciver(dir_path)?;
+    unordered_store(Arc::new(LocalFs::new(dir_path)?), max_items_in_file)
+}
+
+/// Like `unordered_dir_fs` but files are kept in `store`, e.g. `MemoryStore`.
+pub fn unordered_store<T>(
+    store: Arc<dyn SegmentStore>,
+    max_items_in_file: usize,
+) -> io::Result<(DirSender<T>, DirReciver<T>)>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let dir_sender = codec::new_dir_sender(store.clone(), max_items_in_file)?;
+    let dir_reciver = codec::new_dir_reciver(store)?;
     Ok((dir_sender, dir_reciver))
 }
 
+/// Like `unordered_dir_fs` but items are opaque bytes, serialize them with any format you like.
+///
+/// Files are the same as written by `unordered_dir_fs`, so bincode serialized items can be read
+/// with either of them.
+pub fn raw_dir_fs(
+    dir_path: PathBuf,
+    max_items_in_file: usize,
+) -> io::Result<(RawDirSender, RawDirReciver)> {
+    let store: Arc<dyn SegmentStore> = Arc::new(LocalFs::new(dir_path)?);
+    let dir_sender = fs_sender::new_raw_dir_sender(store.clone(), max_items_in_file)?;
+    let dir_reciver = fs_receiver::new_raw_dir_reciver(store)?;
+    Ok((dir_sender, dir_reciver))
+}
+
+/// Read items stored in `dir_path` without removing or modifying anything.
+///
+/// Useful to inspect or replay a backlog. Dir isn't locked and nothing is written to it, so it can
+/// be read-only. Stream waits for more items at the end of a file that is not sealed yet, use
+/// `DirReciver::is_caught_up` to stop there.
+pub fn inspect_dir_fs<T>(dir_path: PathBuf) -> io::Result<DirReciver<T>>
+where
+    T: DeserializeOwned,
+{
+    let mut dir_reciver = codec::new_dir_reciver(Arc::new(LocalFs::read_only(dir_path)?))?;
+    dir_reciver.set_keep_files(true);
+    Ok(dir_reciver)
+}
+
+pub use archive::Archive;
+pub use backend::{Backend, BackendFile, ThreadPool};
+pub use backoff::Backoff;
+use batch::new_batch_sink;
+pub use batch::{Batch, BatchError, BatchSink, Batches};
+pub use breaker::CircuitBreaker;
+pub use compact::{compact, CompactReport};
+pub use dead_letter::{reinject_dead_letters, DeadLetter, DEAD_LETTER_DIR};
+pub use error::Error;
 use fs_sender::{new_send_all, SendAllUnorderedFs};
+pub use fs_sender::{
+    DrainPolicy, SendAllFsErr, SendAllFsFailure, ShutdownHandle, StoreErrorHandler, StorePolicy,
+};
 use futures::{Sink, Stream};
+pub use group_commit::GroupCommit;
+pub use inspect::{
+    list_segments, purge, raw_records, verify_segment, RawRecord, RawRecords, SegmentInfo,
+    SegmentReport,
+};
+use lanes::{new_send_all_lanes, SendAllLanesFs};
+pub use lanes::{Eviction, Lane, LaneScheduling};
+pub use memory::MemoryStore;
+pub use rate_limit::{RateLimit, RateUnit};
+use retry::new_retry_sink;
+pub use retry::{RetryError, RetryPolicy, RetrySink};
+pub use store::{Appender, LocalFs, SegmentStore, Watch};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::IoUring;
 
 /// Extension trait for Sink that allow easy to use this library.
 pub trait SinkFsExt: Sink {
@@ -54,7 +144,7 @@ pub trait SinkFsExt: Sink {
     /// This use SendAllUnorderedFs so it can reorder items!
     ///
     /// # Notes
-    /// It's useful to use it with futures-retry library. See `examples/file_unordered.rs`.
+    /// Use `send_all_fs_retry` to send failed items again. See `examples/file_unordered.rs`.
     fn send_all_fs_backpresure<U>(
         self,
         stream: U,
@@ -69,6 +159,105 @@ pub trait SinkFsExt: Sink {
         let (dir_sender, dir_reciver) = unordered_dir_fs(dir_path, 1000)?;
         Ok(new_send_all(self, stream, dir_sender, dir_reciver))
     }
+
+    /// Like `send_all_fs_backpresure` but items are saved in `store`, e.g. `MemoryStore` to test
+    /// a pipeline without touching disk.
+    fn send_all_store_backpresure<U>(
+        self,
+        stream: U,
+        store: Arc<dyn SegmentStore>,
+    ) -> io::Result<SendAllUnorderedFs<Self, U>>
+    where
+        Self: Sized,
+        U: Stream<Item = Self::SinkItem>,
+        Self::SinkError: From<U::Error>,
+        Self::SinkItem: Serialize + DeserializeOwned,
+    {
+        let (dir_sender, dir_reciver) = unordered_store(store, 1000)?;
+        Ok(new_send_all(self, stream, dir_sender, dir_reciver))
+    }
+
+    /// Like `send_all_fs_backpresure` but items are stored in several lanes.
+    ///
+    /// `classify` returns index of lane for every item, index out of range means the last lane.
+    /// Items from lanes are sent in order given by `scheduling`.
+    ///
+    /// # Warning
+    /// This can reorder items!
+    fn send_all_fs_lanes<U, F>(
+        self,
+        stream: U,
+        lanes: Vec<Lane>,
+        scheduling: LaneScheduling,
+        classify: F,
+    ) -> io::Result<SendAllLanesFs<Self, U, F>>
+    where
+        Self: Sized,
+        U: Stream<Item = Self::SinkItem>,
+        Self::SinkError: From<U::Error>,
+        Self::SinkItem: Serialize + DeserializeOwned,
+        F: FnMut(&Self::SinkItem) -> usize,
+    {
+        new_send_all_lanes(self, stream, lanes, scheduling, classify)
+    }
+
+    /// Like `send_all_fs_backpresure` but for sinks of batches, e.g. bulk API. Items from `stream`
+    /// and items read from `dir_path` are collected into batches limited by `batch`. Size of items
+    /// read from the dir is known from the dir, like in `DirReciver::batches`. See
+    /// [BatchSink](struct.BatchSink.html).
+    ///
+    /// # Warning
+    /// This can reorder items!
+    fn send_all_fs_batched<U, T>(
+        self,
+        stream: U,
+        dir_path: PathBuf,
+        batch: Batch,
+    ) -> io::Result<SendAllUnorderedFs<BatchSink<Self, T>, U>>
+    where
+        Self: Sink<SinkItem = Vec<T>> + Sized,
+        U: Stream<Item = T>,
+        BatchError<Self::SinkError>: From<U::Error>,
+        T: Serialize + DeserializeOwned,
+    {
+        Ok(new_batch_sink(self, batch)
+            .send_all_fs_backpresure(stream, dir_path)?
+            .spill_pending(BatchSink::take_pending)
+            .replay_sized(BatchSink::start_send_sized))
+    }
+
+    /// Send items that failed again according to `policy`. See
+    /// [RetrySink](struct.RetrySink.html).
+    fn with_retry(self, policy: RetryPolicy<Self::SinkError>) -> RetrySink<Self>
+    where
+        Self: Sized,
+        Self::SinkItem: Clone,
+    {
+        new_retry_sink(self, policy)
+    }
+
+    /// Like `send_all_fs_backpresure` but failed items are sent again according to `policy`.
+    /// Items that failed all attempts are written to `dir_path` instead of being dropped.
+    ///
+    /// # Warning
+    /// This can reorder items!
+    fn send_all_fs_retry<U>(
+        self,
+        stream: U,
+        dir_path: PathBuf,
+        policy: RetryPolicy<Self::SinkError>,
+    ) -> io::Result<SendAllUnorderedFs<RetrySink<Self>, U>>
+    where
+        Self: Sized,
+        U: Stream<Item = Self::SinkItem>,
+        RetryError<Self::SinkItem, Self::SinkError>: From<U::Error>,
+        Self::SinkItem: Serialize + DeserializeOwned + Clone,
+    {
+        Ok(self
+            .with_retry(policy)
+            .send_all_fs_backpresure(stream, dir_path)?
+            .spill_failed_items(RetryError::take_exhausted))
+    }
 }
 
 impl<T> SinkFsExt for T where T: Sink {}
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+/// Unit used by [RateLimit](struct.RateLimit.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateUnit {
+    /// Every item costs one token.
+    Items,
+    /// Every item costs as many tokens as it takes bytes when serialized.
+    Bytes,
+}
+
+/// Limit of how fast items read from the dir are sent to the sink.
+///
+/// It's a token bucket. Bucket is refilled with `rate` tokens per second and can hold at most
+/// `burst` tokens. By default items from the stream are not limited.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    rate: u64,
+    burst: u64,
+    unit: RateUnit,
+    limit_live: bool,
+}
+
+impl RateLimit {
+    /// Allow `rate` items per second.
+    ///
+    /// # Panics
+    /// Panics if `rate` is 0.
+    pub fn items_per_sec(rate: u64) -> Self {
+        Self::new(rate, RateUnit::Items)
+    }
+
+    /// Allow `rate` serialized bytes per second.
+    ///
+    /// # Panics
+    /// Panics if `rate` is 0.
+    pub fn bytes_per_sec(rate: u64) -> Self {
+        Self::new(rate, RateUnit::Bytes)
+    }
+
+    fn new(rate: u64, unit: RateUnit) -> Self {
+        assert!(rate > 0, "Rate limit has to be greater than 0");
+        RateLimit {
+            rate,
+            burst: rate,
+            unit,
+            limit_live: false,
+        }
+    }
+
+    /// Set how many tokens can be collected when sink is idle. Default is the rate of one second.
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Count items from the stream too. Items from the stream that exceed the limit are written to
+    /// the dir. Without it items from the stream don't take tokens.
+    pub fn limit_live(mut self, limit_live: bool) -> Self {
+        self.limit_live = limit_live;
+        self
+    }
+
+    /// Unit of this limit.
+    pub fn unit(&self) -> RateUnit {
+        self.unit
+    }
+
+    /// Return true if items from the stream are limited too.
+    pub fn is_limiting_live(&self) -> bool {
+        self.limit_live
+    }
+}
+
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+            limit,
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.rate as f64)
+            .min(self.limit.burst as f64);
+    }
+
+    /// Check if `cost` tokens are available. Returns instant when they will be if not.
+    ///
+    /// Item that costs more than `burst` is allowed when the bucket is full.
+    pub fn check(&mut self, cost: u64) -> Result<(), Instant> {
+        self.refill();
+        let needed = (cost as f64).min(self.limit.burst as f64);
+        if self.tokens >= needed {
+            Ok(())
+        } else {
+            let missing = needed - self.tokens;
+            let wait = Duration::from_secs_f64(missing / self.limit.rate as f64);
+            Err(self.last_refill + wait)
+        }
+    }
+
+    /// Take `cost` tokens. Bucket can go into debt for items bigger than `burst`.
+    pub fn consume(&mut self, cost: u64) {
+        self.tokens -= cost as f64;
+    }
+}
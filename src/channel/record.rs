@@ -0,0 +1,13 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Return true if record written at `written_at` is older than `ttl`.
+pub fn is_expired(written_at: u64, ttl: Duration) -> bool {
+    now_millis().saturating_sub(written_at) > ttl.as_millis() as u64
+}
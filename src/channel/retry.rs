@@ -0,0 +1,225 @@
+use super::backoff::Backoff;
+use custom_error::{add_type_bounds, custom_error};
+use futures::prelude::*;
+use futures::try_ready;
+use log::warn;
+use std::time::Instant;
+use tokio::timer::Delay;
+
+type RetryIf<E> = Box<dyn FnMut(&E) -> bool + Send>;
+
+/// When and how many times [RetrySink](struct.RetrySink.html) sends an item again.
+pub struct RetryPolicy<E> {
+    backoff: Backoff,
+    max_attempts: u32,
+    retry_if: RetryIf<E>,
+}
+
+impl<E> RetryPolicy<E> {
+    /// Retry all errors 3 times, waiting for delay given by `backoff` between attempts.
+    pub fn new(backoff: Backoff) -> Self {
+        RetryPolicy {
+            backoff,
+            max_attempts: 3,
+            retry_if: Box::new(|_| true),
+        }
+    }
+
+    /// Give up after `attempts` failed sends of one item, including the first one.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Retry only errors for which `retry_if` returns true. Other errors reject the item
+    /// immediately.
+    pub fn retry_if<F>(mut self, retry_if: F) -> Self
+    where
+        F: FnMut(&E) -> bool + Send + 'static,
+    {
+        self.retry_if = Box::new(retry_if);
+        self
+    }
+}
+
+custom_error! { pub RetryError<T, E>
+    Exhausted { item: T, attempts: u32, error: E } = "Item failed {attempts} times",
+    Rejected { item: T, error: E } = "Item is rejected by the sink",
+    Sink { error: E } = "Sink error",
+}
+
+impl<T, E> From<E> for RetryError<T, E> {
+    fn from(error: E) -> Self {
+        RetryError::Sink { error }
+    }
+}
+
+impl<T, E> RetryError<T, E> {
+    /// Take the item that failed all attempts, return other errors back.
+    pub fn take_exhausted(self) -> Result<T, Self> {
+        match self {
+            RetryError::Exhausted { item, .. } => Ok(item),
+            other => Err(other),
+        }
+    }
+    /// Take the item rejected with an error that isn't retried together with error message,
+    /// return other errors back.
+    pub fn take_rejected(self) -> Result<(T, String), Self>
+    where
+        E: std::fmt::Display,
+    {
+        match self {
+            RetryError::Rejected { item, error } => Ok((item, error.to_string())),
+            other => Err(other),
+        }
+    }
+}
+
+/// Sink that sends failed items again, see [RetryPolicy](struct.RetryPolicy.html).
+///
+/// Item that failed is kept inside until it is sent again, meanwhile `start_send` returns
+/// `NotReady`. When all attempts fail the item is returned in `RetryError::Exhausted`, use
+/// `SinkFsExt::send_all_fs_retry` to write such items to a dir.
+///
+/// # Notes
+/// Item is cloned before it's sent, because the sink drops it on error. Errors of `poll_complete`
+/// and `close` are not retried.
+pub struct RetrySink<S: Sink> {
+    sink: S,
+    policy: RetryPolicy<S::SinkError>,
+    // item waiting to be sent again.
+    retrying: Option<S::SinkItem>,
+    // number of failed attempts of `retrying` item.
+    attempt: u32,
+    delay: Option<Delay>,
+}
+
+pub fn new_retry_sink<S: Sink>(sink: S, policy: RetryPolicy<S::SinkError>) -> RetrySink<S> {
+    RetrySink {
+        sink,
+        policy,
+        retrying: None,
+        attempt: 0,
+        delay: None,
+    }
+}
+
+impl<S: Sink> RetrySink<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    /// Consume wrapper and return the sink. Item waiting to be sent again is lost.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S> RetrySink<S>
+where
+    S: Sink,
+    S::SinkItem: Clone,
+{
+    fn send(
+        &mut self,
+        item: S::SinkItem,
+    ) -> StartSend<S::SinkItem, RetryError<S::SinkItem, S::SinkError>> {
+        let copy = item.clone();
+        match self.sink.start_send(item) {
+            Ok(AsyncSink::Ready) => {
+                self.attempt = 0;
+                Ok(AsyncSink::Ready)
+            }
+            Ok(not_ready) => Ok(not_ready),
+            Err(error) => self.failed(copy, error).map(|()| AsyncSink::Ready),
+        }
+    }
+
+    /// Keep `item` to send it again after delay or return error if it shouldn't be retried.
+    fn failed(
+        &mut self,
+        item: S::SinkItem,
+        error: S::SinkError,
+    ) -> Result<(), RetryError<S::SinkItem, S::SinkError>> {
+        self.attempt += 1;
+        let attempts = self.attempt;
+        if !(self.policy.retry_if)(&error) {
+            self.attempt = 0;
+            return Err(RetryError::Rejected { item, error });
+        }
+        if attempts >= self.policy.max_attempts {
+            self.attempt = 0;
+            return Err(RetryError::Exhausted {
+                item,
+                attempts,
+                error,
+            });
+        }
+
+        let delay = self.policy.backoff.delay(attempts);
+        warn!(
+            "Sending item failed {} times, retrying in {:?}",
+            attempts, delay
+        );
+        self.delay = Some(Delay::new(Instant::now() + delay));
+        self.retrying = Some(item);
+        Ok(())
+    }
+
+    /// Send item that failed again. Ready when there is no such item.
+    fn poll_retry(&mut self) -> Poll<(), RetryError<S::SinkItem, S::SinkError>> {
+        while let Some(item) = self.retrying.take() {
+            if let Some(delay) = self.delay.as_mut() {
+                match delay.poll() {
+                    Ok(Async::NotReady) => {
+                        self.retrying = Some(item);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(())) => (),
+                    Err(err) => warn!("Timer failed: {}, retrying now", err),
+                }
+                self.delay = None;
+            }
+            if let AsyncSink::NotReady(item) = self.send(item)? {
+                self.retrying = Some(item);
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<S> Sink for RetrySink<S>
+where
+    S: Sink,
+    S::SinkItem: Clone,
+{
+    type SinkItem = S::SinkItem;
+    type SinkError = RetryError<S::SinkItem, S::SinkError>;
+
+    /// Error can be about the item sent before, `item` is kept and sent on the next poll then.
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.poll_retry() {
+            Ok(Async::Ready(())) => self.send(item),
+            Ok(Async::NotReady) => Ok(AsyncSink::NotReady(item)),
+            Err(err) => {
+                self.retrying = Some(item);
+                Err(err)
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_retry());
+        Ok(self.sink.poll_complete()?)
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_retry());
+        Ok(self.sink.close()?)
+    }
+}
@@ -0,0 +1,424 @@
+use super::error::Error;
+use super::frame;
+use log::{debug, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the prefix with length of serialized item.
+pub const SIZE_PREFIX: u64 = 4;
+
+/// Every file starts with `MAGIC` followed by `FORMAT_VERSION` as u32 big endian. Items are stored
+/// after the header and their offsets are counted from its end.
+pub const MAGIC: [u8; 4] = *b"TFSQ";
+
+/// Version of the format of files. Version 1 stores time when item was written with every item.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Size of the header at the start of every file.
+pub const HEADER_LEN: u64 = 8;
+
+/// Every `INDEX_INTERVAL` item has its offset stored in the index file.
+pub const INDEX_INTERVAL: u64 = 64;
+
+/// Size of single index entry: item ordinal and its offset, both u64 big endian.
+const INDEX_ENTRY: usize = 16;
+
+/// Path of file with `index` inside `dir_path`.
+pub fn file_path(dir_path: &Path, index: usize) -> PathBuf {
+    dir_path.join(index.to_string())
+}
+
+/// Indexes of files stored in `dir_path` sorted ascending. Files which names are not numbers are
+/// ignored.
+pub fn file_indexes(dir_path: &Path) -> io::Result<Vec<usize>> {
+    let mut indexes = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(index) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<usize>().ok())
+        {
+            indexes.push(index);
+        }
+    }
+    indexes.sort_unstable();
+    Ok(indexes)
+}
+
+/// Sender marks file as readonly when it is full or closed. Nothing can be appended to such file.
+pub fn is_sealed(path: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(path)?.permissions().readonly())
+}
+
+/// Mark file as readonly, see `is_sealed`.
+pub fn seal(path: &Path) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(path, perms)
+}
+
+/// Path of index file for file in `path`. Index of file `0` is `0.idx`.
+pub fn index_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".idx")
+}
+
+/// Path of temporary file used to create file in `path`. Temporary file of file `0` is `0.tmp`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".tmp")
+}
+
+// `foo.log` becomes `foo.log.idx`, not `foo.idx`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Header of a new file.
+pub fn header() -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header
+}
+
+/// Read the header of file in `path` from `reader` placed at the start of the file.
+///
+/// # Errors
+/// Returns `InvalidData` with `Error::Layout` inside if the header is missing or it's not known,
+/// e.g. the file was written by a version that stored items without time when they were written.
+pub fn read_header<R: Read>(reader: &mut R, path: &Path) -> io::Result<()> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(Error::layout_io(
+                path,
+                "file has no header, it was written by an older version".to_string(),
+            ))
+        }
+        Err(err) => return Err(err),
+    }
+    if header[..4] != MAGIC {
+        return Err(Error::layout_io(
+            path,
+            "file has no header, it was written by an older version".to_string(),
+        ));
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&header[4..]);
+    let version = u32::from_be_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(Error::layout_io(
+            path,
+            format!("unknown format version {}", version),
+        ));
+    }
+    Ok(())
+}
+
+/// Create file in `path` with only the header. It's written to a temporary file first, so file
+/// without a complete header never appears and recivers can check the header of every file.
+pub fn create(path: &Path) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&header())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_dir(path)
+}
+
+/// Make rename of file in `path` durable.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir_path = match path.parent() {
+        Some(dir_path) if !dir_path.as_os_str().is_empty() => dir_path,
+        _ => Path::new("."),
+    };
+    File::open(dir_path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reserve `len` bytes on disk for file in `path` without changing its size, so appending up to
+/// `len` bytes can't fail with no space left. Does nothing where it's not supported.
+#[cfg(target_os = "linux")]
+pub fn preallocate(path: &Path, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        debug!("Can't preallocate {:?}: {}", path, err);
+        return Ok(());
+    }
+    Err(io::Error::new(
+        err.kind(),
+        format!("Can't preallocate {} bytes for {:?}: {}", len, path, err),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(path: &Path, _len: u64) -> io::Result<()> {
+    debug!("Can't preallocate {:?} on this platform", path);
+    Ok(())
+}
+
+/// Remove temporary files left in `dir_path` when program was killed.
+pub fn remove_temp_files(dir_path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") && path.is_file() {
+            warn!("Removing orphaned temporary file {:?}", path);
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove file together with its index.
+pub fn remove(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    remove_index(path);
+    Ok(())
+}
+
+/// Remove index of file in `path`. Index is only a hint, so errors are ignored.
+pub fn remove_index(path: &Path) {
+    let _ = fs::remove_file(index_path(path));
+}
+
+/// Open index of file in `path` for appending new entries.
+pub fn open_index(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(index_path(path))
+}
+
+/// Append entry saying item `ordinal` starts at `offset`.
+pub fn write_index_entry<W: Write>(index: &mut W, ordinal: u64, offset: u64) -> io::Result<()> {
+    let mut entry = [0u8; INDEX_ENTRY];
+    entry[..8].copy_from_slice(&ordinal.to_be_bytes());
+    entry[8..].copy_from_slice(&offset.to_be_bytes());
+    index.write_all(&entry)
+}
+
+/// Entries of index of file in `path` sorted by ordinal. Missing index is empty.
+pub fn read_index(path: &Path) -> io::Result<Vec<(u64, u64)>> {
+    let mut bytes = Vec::new();
+    match File::open(index_path(path)) {
+        Ok(mut index) => index.read_to_end(&mut bytes)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut entry = [0u8; 8];
+    Ok(bytes
+        .chunks_exact(INDEX_ENTRY)
+        .map(|chunk| {
+            entry.copy_from_slice(&chunk[..8]);
+            let ordinal = u64::from_be_bytes(entry);
+            entry.copy_from_slice(&chunk[8..]);
+            (ordinal, u64::from_be_bytes(entry))
+        })
+        .collect())
+}
+
+/// Open file in `path` for reading after its header. Returns the file and size of the items.
+pub fn open_items(path: &Path) -> io::Result<(File, u64)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    read_header(&mut file, path)?;
+    Ok((file, len - HEADER_LEN))
+}
+
+/// Find offset of item `n` in file in `path` without decoding items. Returns ordinal of found item
+/// and its offset. If file has less items position after the last item is returned.
+pub fn seek_item(path: &Path, n: u64) -> io::Result<(u64, u64)> {
+    let (file, len) = open_items(path)?;
+
+    // Entries can point behind the end of file if program was killed before data was written.
+    let start = read_index(path)?
+        .into_iter()
+        .take_while(|&(ordinal, offset)| ordinal <= n && offset <= len)
+        .last()
+        .unwrap_or((0, 0));
+    scan_file(file, len, start, n)
+}
+
+/// Like `seek_item` but without index, for files that are not segments of a dir.
+pub fn seek_item_unindexed(path: &Path, n: u64) -> io::Result<(u64, u64)> {
+    let (file, len) = open_items(path)?;
+    scan_file(file, len, (0, 0), n)
+}
+
+// Skip items from `(ordinal, offset)` until item `n`, `len` is size of the items.
+fn scan_file(mut file: File, len: u64, start: (u64, u64), n: u64) -> io::Result<(u64, u64)> {
+    let (mut ordinal, mut offset) = start;
+    let mut prefix = [0u8; SIZE_PREFIX as usize];
+    while ordinal < n && offset + SIZE_PREFIX <= len {
+        file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+        file.read_exact(&mut prefix)?;
+        let item_end = offset + SIZE_PREFIX + u64::from(u32::from_be_bytes(prefix));
+        if item_end > len {
+            break;
+        }
+        ordinal += 1;
+        offset = item_end;
+    }
+    Ok((ordinal, offset))
+}
+
+/// Ordinal and offset of item `n` in `data` with content of a file. If there are less items, it's
+/// the end of the last complete item.
+pub fn scan_items(data: &[u8], n: u64) -> (u64, u64) {
+    let len = data.len() as u64;
+    let (mut ordinal, mut offset) = (0, 0);
+    let mut prefix = [0u8; SIZE_PREFIX as usize];
+    while ordinal < n && offset + SIZE_PREFIX <= len {
+        prefix.copy_from_slice(&data[offset as usize..(offset + SIZE_PREFIX) as usize]);
+        let item_end = offset + SIZE_PREFIX + u64::from(u32::from_be_bytes(prefix));
+        if item_end > len {
+            break;
+        }
+        ordinal += 1;
+        offset = item_end;
+    }
+    (ordinal, offset)
+}
+
+/// Number of items and bytes stored in a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub items: usize,
+    pub bytes: u64,
+}
+
+/// Count items stored in the file without decoding them.
+///
+/// For a segment of a dir its index is rebuilt and incomplete item at the end (left when program
+/// was killed during write) is truncated, so new items can be appended. Other files are never
+/// modified, incomplete item is an error. Empty file gets the header.
+///
+/// # Errors
+/// Returns `InvalidData` with `Error::Layout` inside if the file has no valid header, see
+/// `read_header`.
+pub fn recover(path: &Path, dir_segment: bool) -> io::Result<FileStats> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&header())?;
+        file.seek(SeekFrom::Start(0))?;
+    }
+    read_header(&mut file, path)?;
+    let len = file.metadata()?.len() - HEADER_LEN;
+
+    let mut index: Box<dyn Write> = if dir_segment {
+        Box::new(BufWriter::new(File::create(index_path(path))?))
+    } else {
+        Box::new(io::sink())
+    };
+    let mut stats = FileStats::default();
+    let mut prefix = [0u8; SIZE_PREFIX as usize];
+    while stats.bytes + SIZE_PREFIX <= len {
+        file.seek(SeekFrom::Start(HEADER_LEN + stats.bytes))?;
+        file.read_exact(&mut prefix)?;
+        let item_end = stats.bytes + SIZE_PREFIX + u64::from(u32::from_be_bytes(prefix));
+        if item_end > len {
+            break;
+        }
+        if stats.items as u64 % INDEX_INTERVAL == 0 {
+            write_index_entry(&mut index, stats.items as u64, stats.bytes)?;
+        }
+        stats.items += 1;
+        stats.bytes = item_end;
+    }
+    index.flush()?;
+
+    if stats.bytes < len && !dir_segment {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Incomplete item at the end of {:?} ({} bytes), remove it to append",
+                path,
+                len - stats.bytes
+            ),
+        ));
+    }
+    if stats.bytes < len {
+        warn!(
+            "Truncating incomplete item at the end of {:?} ({} bytes)",
+            path,
+            len - stats.bytes
+        );
+        file.set_len(HEADER_LEN + stats.bytes)?;
+    }
+    Ok(stats)
+}
+
+/// Writes a new sealed file through a temporary file.
+pub struct SegmentWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    index: Vec<u8>,
+    pub items: u64,
+    bytes: u64,
+}
+
+impl SegmentWriter {
+    pub fn new(dir_path: &Path, index: usize) -> io::Result<Self> {
+        let path = file_path(dir_path, index);
+        let mut file = BufWriter::new(File::create(temp_path(&path))?);
+        file.write_all(&header())?;
+        Ok(SegmentWriter {
+            file,
+            path,
+            index: Vec::new(),
+            items: 0,
+            bytes: 0,
+        })
+    }
+
+    pub fn write(&mut self, written_at: u64, payload: &[u8]) -> io::Result<()> {
+        if self.items % INDEX_INTERVAL == 0 {
+            write_index_entry(&mut self.index, self.items, self.bytes)?;
+        }
+        let size = frame::frame_size(payload)?;
+        self.file.write_all(&size.to_be_bytes())?;
+        self.file.write_all(&written_at.to_le_bytes())?;
+        self.file.write_all(payload)?;
+        self.items += 1;
+        self.bytes += SIZE_PREFIX + u64::from(size);
+        Ok(())
+    }
+
+    /// Seal the file and move it to its place.
+    pub fn finish(self) -> io::Result<()> {
+        let temp_path = temp_path(&self.path);
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        seal(&temp_path)?;
+        fs::rename(&temp_path, &self.path)?;
+        sync_dir(&self.path)?;
+        fs::write(index_path(&self.path), &self.index)
+    }
+}
@@ -0,0 +1,360 @@
+use super::backend::{Backend, BackendFile, ThreadPool};
+use super::error::Error;
+use super::lock::DirLock;
+#[cfg(all(unix, feature = "mmap"))]
+use super::mmap::MappedFile;
+use super::segment;
+use futures::prelude::*;
+use futures::sync::mpsc;
+use log::{debug, trace};
+use notify::{self, Watcher};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Stream with an item every time a segment changes, see `SegmentStore::watch`.
+pub type Watch = Box<dyn Stream<Item = (), Error = io::Error> + Send>;
+
+/// Segment opened for appending by `SegmentStore::append`.
+pub struct Appender {
+    /// Appends to the end of the segment. Segment is sealed by `SegmentStore::seal`.
+    pub file: Box<dyn BackendFile>,
+    /// Sparse index of item offsets, see `SegmentStore::seek`.
+    pub index: Box<dyn Write + Send>,
+    /// Number of items stored in the segment.
+    pub items: u64,
+    /// Size of the items stored in the segment.
+    pub bytes: u64,
+    /// Size of the segment header, items are stored after it.
+    pub header: u64,
+}
+
+/// Where dir senders and recivers keep segments, files with items numbered from 0.
+///
+/// Sender creates a segment, appends items to it and seals it when it's full. Reciver lists
+/// segments, opens them, watches the one that is not sealed yet and removes them when they are
+/// read. [LocalFs](struct.LocalFs.html) keeps segments in files in a dir,
+/// [MemoryStore](struct.MemoryStore.html) keeps them in memory.
+pub trait SegmentStore: Send + Sync {
+    /// Dir with the segments if they are files on local fs. Archive, quarantine and dead letters
+    /// are supported only with it.
+    fn dir_path(&self) -> Option<&Path>;
+
+    /// Path of segment `index`, used in errors and logs.
+    fn path(&self, index: usize) -> PathBuf;
+
+    /// Indexes of all segments in ascending order.
+    fn list(&self) -> io::Result<Vec<usize>>;
+
+    /// Create empty segment `index` unless it exists.
+    fn create(&self, index: usize) -> io::Result<()>;
+
+    /// Read and write segments opened from now on with `backend`. Stores without files ignore
+    /// it.
+    fn set_backend(&self, _backend: Arc<dyn Backend>) {}
+
+    /// Open existing segment `index` for appending. Incomplete item left at the end when program
+    /// was killed during write is removed.
+    fn append(&self, index: usize) -> io::Result<Appender>;
+
+    /// Seal segment `index`, nothing can be appended to it anymore. Items appended to it have to
+    /// be flushed before.
+    fn seal(&self, index: usize) -> io::Result<()>;
+
+    /// Reserve `len` bytes for segment `index`. Stores that can't do it ignore it.
+    fn preallocate(&self, _index: usize, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Return true if segment `index` exists and it's sealed.
+    fn is_sealed(&self, index: usize) -> io::Result<bool>;
+
+    /// Time when segment `index` was modified last time.
+    fn modified(&self, index: usize) -> io::Result<SystemTime>;
+
+    /// Read segment `index` from `offset`.
+    ///
+    /// # Errors
+    /// Returns `NotFound` if the segment doesn't exist.
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn BackendFile>>;
+
+    /// Ordinal and offset of item `n` in segment `index`, or of the end of the segment if it has
+    /// less items.
+    fn seek(&self, index: usize, n: u64) -> io::Result<(u64, u64)>;
+
+    /// Remove segment `index`.
+    fn remove(&self, index: usize) -> io::Result<()>;
+
+    /// Watch segment `index` for appended items and sealing.
+    fn watch(&self, index: usize) -> io::Result<Watch>;
+}
+
+/// Segments stored in files in a dir on local fs.
+///
+/// Dir is locked shared while the store exists, so offline operations like `compact` don't touch
+/// it. Files are read and written by `ThreadPool` unless other backend is set.
+pub struct LocalFs {
+    path: PathBuf,
+    backend: RwLock<Arc<dyn Backend>>,
+    // true if `path` is a single file used as every segment, see `LocalFs::file`.
+    single_file: bool,
+    // true if segments are only read, see `LocalFs::read_only`.
+    read_only: bool,
+    // `None` for a single file and a read-only store, they aren't mapped since nothing keeps the
+    // files from being truncated.
+    #[cfg_attr(not(all(unix, feature = "mmap")), allow(dead_code))]
+    lock: Option<DirLock>,
+}
+
+impl LocalFs {
+    /// Store segments in `dir_path`, it has to exist.
+    pub fn new(dir_path: PathBuf) -> io::Result<Self> {
+        if !dir_path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Path {:?} dosen't represent dir", dir_path),
+            ));
+        }
+
+        Ok(LocalFs {
+            lock: Some(DirLock::shared(&dir_path)?),
+            path: dir_path,
+            backend: RwLock::new(Arc::new(ThreadPool)),
+            single_file: false,
+            read_only: false,
+        })
+    }
+
+    /// Read segments in `dir_path` without locking it, so the dir is never modified and it can
+    /// be read-only. Creating, appending, sealing and removing segments fails with
+    /// `PermissionDenied`.
+    pub fn read_only(dir_path: PathBuf) -> io::Result<Self> {
+        if !dir_path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Path {:?} dosen't represent dir", dir_path),
+            ));
+        }
+
+        Ok(LocalFs {
+            path: dir_path,
+            backend: RwLock::new(Arc::new(ThreadPool)),
+            single_file: false,
+            read_only: true,
+            lock: None,
+        })
+    }
+
+    /// Store with a single file in `path`, every index refers to it. The file has no index and
+    /// it's never truncated, see `segment::recover`.
+    pub(crate) fn file(path: PathBuf) -> Self {
+        LocalFs {
+            path,
+            backend: RwLock::new(Arc::new(ThreadPool)),
+            single_file: true,
+            read_only: false,
+            lock: None,
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Store in {:?} is read-only", self.path),
+            ));
+        }
+        Ok(())
+    }
+
+    fn backend(&self) -> Arc<dyn Backend> {
+        self.backend
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+}
+
+impl SegmentStore for LocalFs {
+    fn dir_path(&self) -> Option<&Path> {
+        if self.single_file {
+            None
+        } else {
+            Some(&self.path)
+        }
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        if self.single_file {
+            self.path.clone()
+        } else {
+            segment::file_path(&self.path, index)
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<usize>> {
+        if !self.single_file {
+            segment::file_indexes(&self.path)
+        } else if self.path.exists() {
+            Ok(vec![0])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn create(&self, index: usize) -> io::Result<()> {
+        self.check_writable()?;
+        let path = self.path(index);
+        if !path.exists() {
+            if !self.single_file {
+                // index could be left when program was killed before the file was created.
+                segment::remove_index(&path);
+            }
+            segment::create(&path)?;
+        }
+        Ok(())
+    }
+
+    fn set_backend(&self, backend: Arc<dyn Backend>) {
+        *self.backend.write().unwrap_or_else(|err| err.into_inner()) = backend;
+    }
+
+    fn append(&self, index: usize) -> io::Result<Appender> {
+        self.check_writable()?;
+        let path = self.path(index);
+        let stats = segment::recover(&path, !self.single_file)?;
+        if stats.items > 0 {
+            debug!(
+                "Appending to {:?} with {} items ({} bytes)",
+                path, stats.items, stats.bytes
+            );
+        }
+
+        let write_fd_std = std::fs::OpenOptions::new().append(true).open(&path)?;
+        Ok(Appender {
+            file: self.backend().open(write_fd_std)?,
+            index: if self.single_file {
+                Box::new(io::sink())
+            } else {
+                Box::new(segment::open_index(&path)?)
+            },
+            items: stats.items as u64,
+            bytes: stats.bytes,
+            header: segment::HEADER_LEN,
+        })
+    }
+
+    fn seal(&self, index: usize) -> io::Result<()> {
+        self.check_writable()?;
+        segment::seal(&self.path(index))
+    }
+
+    fn preallocate(&self, index: usize, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        segment::preallocate(&self.path(index), len)
+    }
+
+    fn is_sealed(&self, index: usize) -> io::Result<bool> {
+        let path = self.path(index);
+        Ok(path.exists() && segment::is_sealed(&path)?)
+    }
+
+    fn modified(&self, index: usize) -> io::Result<SystemTime> {
+        std::fs::metadata(self.path(index))?.modified()
+    }
+
+    /// Sealed files of a locked dir are memory mapped with `mmap` feature.
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn BackendFile>> {
+        let (mut read_fd_std, _len) = segment::open_items(&self.path(index))?;
+        #[cfg(all(unix, feature = "mmap"))]
+        {
+            if self.lock.is_some() && read_fd_std.metadata()?.permissions().readonly() {
+                return Ok(Box::new(MappedFile::new(
+                    &read_fd_std,
+                    segment::HEADER_LEN + offset,
+                )?));
+            }
+        }
+        if offset > 0 {
+            read_fd_std.seek(SeekFrom::Start(segment::HEADER_LEN + offset))?;
+        }
+        self.backend().open(read_fd_std)
+    }
+
+    fn seek(&self, index: usize, n: u64) -> io::Result<(u64, u64)> {
+        if self.single_file {
+            segment::seek_item_unindexed(&self.path, n)
+        } else {
+            segment::seek_item(&self.path(index), n)
+        }
+    }
+
+    fn remove(&self, index: usize) -> io::Result<()> {
+        self.check_writable()?;
+        if self.single_file {
+            std::fs::remove_file(&self.path)
+        } else {
+            segment::remove(&self.path(index))
+        }
+    }
+
+    fn watch(&self, index: usize) -> io::Result<Watch> {
+        let watcher = FileWatcher::watch_path(self.path(index)).map_err(Error::watcher_io)?;
+        Ok(Box::new(watcher))
+    }
+}
+
+type Rx<T> = mpsc::UnboundedReceiver<T>;
+
+struct FileWatcher {
+    rx: Rx<notify::RawEvent>,
+    // we don't use them but prevent call drop.
+    _thread: std::thread::JoinHandle<()>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl FileWatcher {
+    pub fn watch_path<P: AsRef<Path>>(path: P) -> Result<FileWatcher, notify::Error> {
+        let (tx, rx) = mpsc::unbounded();
+        let (std_tx, std_rx) = std_mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Ok(item) = std_rx.recv() {
+                if let Err(err) = tx.unbounded_send(item) {
+                    // other side is droped.
+                    trace!("Reciver will never recive {:?}", err);
+                    break;
+                }
+            }
+        });
+
+        let mut watcher = notify::raw_watcher(std_tx)?;
+        watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
+        Ok(FileWatcher {
+            rx,
+            _thread: thread,
+            _watcher: watcher,
+        })
+    }
+}
+
+impl Stream for FileWatcher {
+    type Item = ();
+    type Error = io::Error;
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        let item = self.rx.poll();
+        match item {
+            Ok(Async::Ready(Some(event))) => {
+                debug!("Event from os {:?}", event);
+                match event.op {
+                    Err(err) => Err(Error::watcher_io(err)),
+                    Ok(_) => Ok(Async::Ready(Some(()))),
+                }
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => unreachable!(),
+        }
+    }
+}
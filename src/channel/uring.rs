@@ -0,0 +1,453 @@
+use super::backend::{Backend, BackendFile, ThreadPool};
+use futures::task::{self, Task};
+use futures::{try_ready, Async, Poll};
+use io_uring::{opcode, squeue, types};
+use log::{error, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// user data of the operation that stops the completion thread.
+const STOP: u64 = u64::MAX;
+
+const READ_SIZE: usize = 64 * 1024;
+const MAX_WRITE: usize = 4 * 1024 * 1024;
+
+/// Operation submitted to the kernel.
+struct Op {
+    // memory the kernel reads or writes, it has to live until the operation completes.
+    buffer: Vec<u8>,
+    // file of the operation, its fd can't be closed and reused before the kernel uses it.
+    _file: Option<Arc<File>>,
+    result: Option<i32>,
+    task: Option<Task>,
+    // nobody waits for the result, it's removed when completed.
+    abandoned: bool,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+struct Ring {
+    ring: io_uring::IoUring,
+    // held while the submission queue is used, only one may exist at once.
+    sq: Mutex<()>,
+    ops: Mutex<HashMap<u64, Op>>,
+    next_id: AtomicU64,
+    // true when the completion thread failed, nothing would complete new operations.
+    broken: AtomicBool,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Ring> {
+        let ring = io_uring::IoUring::new(entries)?;
+        // read and write operations came with this feature.
+        if !ring.params().is_feature_rw_cur_pos() {
+            return Err(io::Error::other(
+                "io_uring can't read and write files, Linux 5.6 is needed",
+            ));
+        }
+        Ok(Ring {
+            ring,
+            sq: Mutex::new(()),
+            ops: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            broken: AtomicBool::new(false),
+        })
+    }
+
+    /// Put `entry` to the submission queue and submit it with entries left by failed submits.
+    /// Returns false if the queue is full and they still can't be submitted.
+    ///
+    /// # Safety
+    /// Memory and fd used by `entry` have to be valid until it completes.
+    unsafe fn push(&self, entry: &squeue::Entry) -> io::Result<bool> {
+        let _sq = lock(&self.sq);
+        let push = || {
+            // SAFETY: `_sq` is held, so no other submission queue exists. Caller keeps memory
+            // of the entry valid.
+            let mut sq = unsafe { self.ring.submission_shared() };
+            unsafe { sq.push(entry) }.is_ok()
+        };
+        if !push() && (self.submit().is_err() || !push()) {
+            return Ok(false);
+        }
+        self.submit()?;
+        Ok(true)
+    }
+
+    fn submit(&self) -> io::Result<()> {
+        loop {
+            match self.ring.submit() {
+                Ok(_) => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                // completions overflowed, entry stays in the queue and it's submitted when the
+                // completion thread takes them.
+                Err(ref err) if err.raw_os_error() == Some(libc::EBUSY) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Submit operation `entry` that uses `buffer` and `file`. Returns id of the operation.
+    fn submit_op(
+        &self,
+        entry: squeue::Entry,
+        buffer: Vec<u8>,
+        file: Option<Arc<File>>,
+    ) -> io::Result<u64> {
+        if self.broken.load(Ordering::Acquire) {
+            return Err(io::Error::other("io_uring completion thread failed"));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = entry.user_data(id);
+        // moving the buffer to the op doesn't move its memory.
+        lock(&self.ops).insert(
+            id,
+            Op {
+                buffer,
+                _file: file,
+                result: None,
+                task: None,
+                abandoned: false,
+            },
+        );
+        // SAFETY: the buffer and the file are kept in `ops` until the operation completes,
+        // abandoned operations too.
+        match unsafe { self.push(&entry) } {
+            Ok(true) => Ok(id),
+            Ok(false) => {
+                lock(&self.ops).remove(&id);
+                Err(io::Error::other("io_uring submission queue is full"))
+            }
+            Err(err) => {
+                // entry is submitted later, so the buffer has to stay.
+                self.abandon(id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Ready with result of operation `id` and its buffer.
+    fn poll_op(&self, id: u64) -> Poll<(i32, Vec<u8>), io::Error> {
+        let mut ops = lock(&self.ops);
+        let op = ops
+            .get_mut(&id)
+            .ok_or_else(|| io::Error::other("io_uring operation is lost"))?;
+        match op.result {
+            Some(result) => {
+                let buffer = mem::take(&mut op.buffer);
+                ops.remove(&id);
+                Ok(Async::Ready((result, buffer)))
+            }
+            None => {
+                op.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    /// Forget operation `id`, it's removed when completed.
+    fn abandon(&self, id: u64) {
+        let mut ops = lock(&self.ops);
+        if let Some(op) = ops.get_mut(&id) {
+            if op.result.is_some() {
+                ops.remove(&id);
+            } else {
+                op.abandoned = true;
+            }
+        }
+    }
+
+    /// Wait for completed operations and wake their tasks until `STOP` and all operations are
+    /// completed.
+    fn complete(&self) {
+        let mut stopping = false;
+        loop {
+            if stopping && lock(&self.ops).is_empty() {
+                return;
+            }
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::Interrupted
+                        || err.raw_os_error() == Some(libc::EBUSY) => {}
+                Err(err) => {
+                    error!("Waiting for io_uring failed: {}", err);
+                    self.broken.store(true, Ordering::Release);
+                    self.fail_all();
+                    return;
+                }
+            }
+
+            let mut ops = lock(&self.ops);
+            // SAFETY: only this thread uses the completion queue.
+            for cqe in unsafe { self.ring.completion_shared() } {
+                if cqe.user_data() == STOP {
+                    stopping = true;
+                } else {
+                    complete_op(&mut ops, cqe.user_data(), cqe.result());
+                }
+            }
+        }
+    }
+
+    /// Complete all operations with error, nothing will complete them anymore.
+    fn fail_all(&self) {
+        let mut ops = lock(&self.ops);
+        let ids: Vec<u64> = ops.keys().cloned().collect();
+        for id in ids {
+            complete_op(&mut ops, id, -libc::EIO);
+        }
+    }
+}
+
+fn complete_op(ops: &mut HashMap<u64, Op>, id: u64, result: i32) {
+    let abandoned = match ops.get_mut(&id) {
+        Some(op) => {
+            op.result = Some(result);
+            if let Some(task) = op.task.take() {
+                task.notify();
+            }
+            op.abandoned
+        }
+        None => false,
+    };
+    if abandoned {
+        ops.remove(&id);
+    }
+}
+
+/// Ring used by files, stops the completion thread when the last file and `IoUring` are dropped.
+struct Shared {
+    ring: Arc<Ring>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let entry = opcode::Nop::new().build().user_data(STOP);
+        // SAFETY: nop uses no memory.
+        match unsafe { self.ring.push(&entry) } {
+            Ok(true) => (),
+            Ok(false) => error!("Can't stop io_uring completion thread: queue is full"),
+            Err(err) => error!("Can't stop io_uring completion thread: {}", err),
+        }
+    }
+}
+
+/// Backend that reads and writes files with io_uring, Linux 5.6 or newer is needed.
+///
+/// Reads and writes are submitted without blocking and a thread waits for their completion.
+/// Truncating and other rare operations are done directly. Files are read and written by
+/// `ThreadPool` if io_uring can't be used.
+///
+/// # Notes
+/// `write` returns as soon as the write is submitted, its error is returned by the next write or
+/// flush.
+#[derive(Clone)]
+pub struct IoUring {
+    // `None` if io_uring can't be used.
+    shared: Option<Arc<Shared>>,
+}
+
+impl IoUring {
+    /// Create io_uring for up to `entries` operations submitted at once. Falls back to
+    /// `ThreadPool` if it fails, e.g. kernel is too old or io_uring is disabled.
+    pub fn new(entries: u32) -> Self {
+        match IoUring::start(entries.max(1)) {
+            Ok(shared) => IoUring {
+                shared: Some(shared),
+            },
+            Err(err) => {
+                warn!("Can't use io_uring, falling back to thread pool: {}", err);
+                IoUring { shared: None }
+            }
+        }
+    }
+
+    fn start(entries: u32) -> io::Result<Arc<Shared>> {
+        let ring = Arc::new(Ring::new(entries)?);
+        let thread_ring = ring.clone();
+        std::thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || thread_ring.complete())?;
+        Ok(Arc::new(Shared { ring }))
+    }
+
+    /// False if files are read and written by `ThreadPool`, see `new`.
+    pub fn is_io_uring(&self) -> bool {
+        self.shared.is_some()
+    }
+}
+
+impl Backend for IoUring {
+    fn open(&self, mut file: File) -> io::Result<Box<dyn BackendFile>> {
+        let shared = match self.shared {
+            Some(ref shared) => shared.clone(),
+            None => return ThreadPool.open(file),
+        };
+        Ok(Box::new(UringFile {
+            pos: file.stream_position()?,
+            file: Arc::new(file),
+            shared,
+            pending: None,
+            read: Vec::new(),
+            read_pos: 0,
+        }))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Read,
+    Write,
+    Sync,
+}
+
+struct UringFile {
+    file: Arc<File>,
+    // offset of the next read or write. Kernel doesn't move position of the file after a short
+    // write, so operations use their offset instead.
+    pos: u64,
+    shared: Arc<Shared>,
+    // operation in progress and its id.
+    pending: Option<(Kind, u64)>,
+    // data read and not returned yet.
+    read: Vec<u8>,
+    read_pos: usize,
+}
+
+fn would_block(poll: Poll<(), io::Error>) -> io::Result<()> {
+    match poll? {
+        Async::Ready(()) => Ok(()),
+        Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+impl UringFile {
+    fn submit(&mut self, kind: Kind, mut buffer: Vec<u8>) -> io::Result<()> {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let entry = match kind {
+            Kind::Read => opcode::Read::new(fd, buffer.as_mut_ptr(), buffer.len() as u32)
+                .offset(self.pos)
+                .build(),
+            Kind::Write => opcode::Write::new(fd, buffer.as_ptr(), buffer.len() as u32)
+                .offset(self.pos)
+                .build(),
+            // whole file
+            Kind::Sync => opcode::Fsync::new(fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+        };
+        let id = self
+            .shared
+            .ring
+            .submit_op(entry, buffer, Some(self.file.clone()))?;
+        self.pending = Some((kind, id));
+        Ok(())
+    }
+
+    /// Ready when operation in progress is completed. Short write is continued.
+    fn poll_pending(&mut self) -> Poll<(), io::Error> {
+        while let Some((kind, id)) = self.pending {
+            let (result, mut buffer) = try_ready!(self.shared.ring.poll_op(id));
+            self.pending = None;
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            let n = result as usize;
+            if kind != Kind::Sync {
+                self.pos += n as u64;
+            }
+            match kind {
+                Kind::Read => {
+                    buffer.truncate(n);
+                    self.read = buffer;
+                    self.read_pos = 0;
+                }
+                Kind::Write if n < buffer.len() => {
+                    if n == 0 {
+                        return Err(io::ErrorKind::WriteZero.into());
+                    }
+                    buffer.drain(..n);
+                    self.submit(Kind::Write, buffer)?;
+                }
+                Kind::Write | Kind::Sync => (),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Read for UringFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos == self.read.len() {
+            if self.pending.map(|(kind, _)| kind) != Some(Kind::Read) {
+                would_block(self.poll_pending())?;
+                self.submit(Kind::Read, vec![0; READ_SIZE.max(buf.len()).min(MAX_WRITE)])?;
+            }
+            would_block(self.poll_pending())?;
+        }
+        let n = buf.len().min(self.read.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for UringFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        would_block(self.poll_pending())?;
+        let n = buf.len().min(MAX_WRITE);
+        if n > 0 {
+            self.submit(Kind::Write, buf[..n].to_vec())?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        would_block(self.poll_pending())
+    }
+}
+
+impl AsyncRead for UringFile {}
+
+impl AsyncWrite for UringFile {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.poll_pending()
+    }
+}
+
+impl BackendFile for UringFile {
+    fn poll_is_sealed(&mut self) -> Poll<bool, io::Error> {
+        Ok(self.file.metadata()?.permissions().readonly().into())
+    }
+
+    fn poll_set_len(&mut self, len: u64) -> Poll<(), io::Error> {
+        try_ready!(self.poll_pending());
+        self.file.set_len(len)?;
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_sync_data(&mut self) -> Poll<(), io::Error> {
+        if self.pending.map(|(kind, _)| kind) != Some(Kind::Sync) {
+            try_ready!(self.poll_pending());
+            self.submit(Kind::Sync, Vec::new())?;
+        }
+        self.poll_pending()
+    }
+}
+
+impl Drop for UringFile {
+    fn drop(&mut self) {
+        if let Some((_, id)) = self.pending {
+            self.shared.ring.abandon(id);
+        }
+    }
+}
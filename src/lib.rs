@@ -4,9 +4,9 @@
 //! when program is restarted.
 //!
 //! The idea is to start saving on disk when sink return Async::NotReady(item). `item` has to impl
-//! Serialize and Deserialize. At this moment [async
-//! bincode](https://crates.io/crates/async-bincode) is used under hood. It's designed to be used
-//! with [futures-retry](https://crates.io/crates/futures-retry) Sink implementation.
+//! Serialize and Deserialize, it's serialized with bincode. Use `channel::raw_dir_fs` to store
+//! bytes serialized with another format. Failed items can be sent again with `channel::RetrySink`,
+//! items that failed all attempts are saved on disk too.
 pub mod channel;
 
 // TODO before #![deny(missing_docs)]
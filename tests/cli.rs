@@ -0,0 +1,110 @@
+#![cfg(feature = "cli")]
+
+use assert_cmd::Command;
+use futures::stream::iter_ok;
+use std::io;
+use std::path::Path;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::unordered_dir_fs;
+
+fn send_items(dir_path: &Path, items: usize, max_items_in_file: usize) {
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..items).map(|i| format!("item {}", i)).collect();
+    let (s, _r) = unordered_dir_fs::<String>(dir_path.to_path_buf(), max_items_in_file).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items)))
+            .unwrap(),
+    );
+}
+
+fn cli(args: &[&str], dir_path: &Path) -> assert_cmd::assert::Assert {
+    Command::cargo_bin("tokio-fs-stream")
+        .unwrap()
+        .arg(args[0])
+        .arg(dir_path)
+        .args(&args[1..])
+        .assert()
+}
+
+fn stdout(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8(assert.get_output().stdout.clone()).unwrap()
+}
+
+#[test]
+fn list_shows_files() {
+    let dir = tempfile::tempdir().unwrap();
+    send_items(dir.path(), 5, 3);
+
+    let assert = cli(&["list"], dir.path()).success();
+    let lines: Vec<Vec<String>> = stdout(&assert)
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().map(String::from).collect())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0][..2], ["0", "3"]);
+    assert_eq!(lines[0][3], "true");
+    assert_eq!(lines[1][..2], ["1", "2"]);
+}
+
+#[test]
+fn dump_prints_items() {
+    let dir = tempfile::tempdir().unwrap();
+    send_items(dir.path(), 4, 3);
+
+    let assert = cli(&["dump", "--type", "string"], dir.path()).success();
+    let lines: Vec<serde_json::Value> = stdout(&assert)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0]["file"], 0);
+    assert_eq!(lines[0]["offset"], 0);
+    assert_eq!(lines[3]["file"], 1);
+    assert_eq!(lines[3]["ordinal"], 0);
+    assert_eq!(lines[3]["item"], "item 3");
+
+    cli(&["dump", "--type", "u128"], dir.path()).failure();
+}
+
+#[test]
+fn verify_reports_broken_files() {
+    let dir = tempfile::tempdir().unwrap();
+    send_items(dir.path(), 3, 3);
+    let assert = cli(&["verify"], dir.path()).success();
+    assert_eq!(stdout(&assert), "0: ok, 3 items\n");
+
+    // header and incomplete item left by a crash
+    let mut content = b"TFSQ\0\0\0\x01".to_vec();
+    content.extend_from_slice(&[0, 0, 0, 20, 1, 2]);
+    std::fs::write(dir.path().join("1"), content).unwrap();
+    let assert = cli(&["verify"], dir.path()).failure();
+    assert!(stdout(&assert).contains("1: 0 items, 6 trailing bytes"));
+}
+
+#[test]
+fn compact_and_purge_change_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    send_items(dir.path(), 6, 1);
+
+    let assert = cli(&["compact", "--max-items", "4"], dir.path()).success();
+    assert_eq!(stdout(&assert), "6 items moved from 6 files to 2 files\n");
+
+    let assert = cli(&["purge", "--all"], dir.path()).success();
+    assert_eq!(stdout(&assert).lines().count(), 2);
+    assert!(std::fs::read_dir(dir.path()).unwrap().all(|entry| entry
+        .unwrap()
+        .file_name()
+        .to_str()
+        .unwrap()
+        .starts_with('.')));
+}
+
+#[test]
+fn invalid_arguments_print_usage() {
+    let dir = tempfile::tempdir().unwrap();
+    let assert = cli(&["list", "--all"], dir.path()).failure();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Usage: tokio-fs-stream"));
+}
@@ -1,7 +1,15 @@
+use bytes::Bytes;
+use futures::future::{self, loop_fn, poll_fn, Loop};
 use futures::stream::iter_ok;
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::prelude::*;
-use tokio_fs_stream::channel::unordered_dir_fs;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{
+    inspect_dir_fs, raw_dir_fs, unordered_dir_fs, unordered_store, Archive, Batch, Error,
+    GroupCommit, LocalFs, MemoryStore, SegmentStore,
+};
 
 #[test]
 fn dir_sender_naive() {
@@ -39,3 +47,591 @@ fn dir_sender_naive() {
         Ok(())
     }));
 }
+
+// Collect all items and give the stream back.
+fn collect_keep<S>(stream: S) -> impl Future<Item = (Vec<S::Item>, S), Error = S::Error>
+where
+    S: Stream,
+{
+    loop_fn((Vec::new(), stream), |(mut items, stream)| {
+        stream
+            .into_future()
+            .map(|(opt_item, stream)| match opt_item {
+                Some(item) => {
+                    items.push(item);
+                    Loop::Continue((items, stream))
+                }
+                None => Loop::Break((items, stream)),
+            })
+            .map_err(|(err, _stream)| err)
+    })
+}
+
+#[test]
+fn dir_reciver_skips_expired_items() {
+    let dir = tempfile::tempdir().unwrap();
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 100).unwrap();
+    r.set_ttl(Duration::from_millis(50));
+
+    let mut rt = Runtime::new().unwrap();
+    let s = rt
+        .block_on(
+            s.send("old 1".to_string())
+                .and_then(|s| s.send("old 2".to_string())),
+        )
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(vec!["fresh".to_string()])))
+            .unwrap(),
+    );
+
+    let (readed, r) = rt.block_on(collect_keep(r)).unwrap();
+    assert_eq!(readed, vec!["fresh".to_string()]);
+    assert_eq!(r.expired_items(), 2);
+}
+
+#[test]
+fn dir_reciver_removes_expired_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 100).unwrap();
+    r.set_ttl(Duration::from_millis(50));
+
+    let mut rt = Runtime::new().unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(vec!["old".to_string()])))
+            .unwrap(),
+    );
+    std::thread::sleep(Duration::from_millis(100));
+
+    let (readed, r) = rt.block_on(collect_keep(r)).unwrap();
+    assert!(readed.is_empty());
+    assert_eq!(r.expired_files(), 1);
+    assert!(!dir.path().join("0").exists());
+}
+
+#[test]
+fn dir_sender_continues_last_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..7).map(|i| format!("item {}", i)).collect();
+
+    // program killed without closing the sender
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    let send = iter_ok::<_, ()>(items[..3].to_vec()).fold(s, |s, item| s.send(item).map_err(drop));
+    drop(rt.block_on(send).unwrap());
+
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items[3..].to_vec())))
+            .unwrap(),
+    );
+    // first file got only 2 more items
+    assert!(dir.path().join("1").exists());
+
+    // all files are sealed, new file is created with the first item
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    assert!(!dir.path().join("2").exists());
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, items);
+
+    drop(rt.block_on(s.send("item 7".to_string())).unwrap());
+    assert!(dir.path().join("2").exists());
+}
+
+#[test]
+fn dir_sender_group_commit_applies_backpressure() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let mut rt = Runtime::new().unwrap();
+    let (s, r) = unordered_dir_fs::<u32>(dir.path().to_path_buf(), 100).unwrap();
+    // size prefix, time and u32 take 16 bytes
+    let mut s = s.group_commit(GroupCommit::new(16).linger(Duration::from_secs(60)));
+
+    let s = rt
+        .block_on(future::lazy(move || {
+            assert!(s.start_send(1).unwrap().is_ready());
+            Ok::<_, Error>(s)
+        }))
+        .unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8);
+
+    // the first item is written before the next one is accepted
+    let mut item = Some(2);
+    let mut s = Some(s);
+    let mut s = rt
+        .block_on(poll_fn(move || {
+            match s.as_mut().unwrap().start_send(item.take().unwrap())? {
+                AsyncSink::Ready => {}
+                AsyncSink::NotReady(not_sent) => {
+                    item = Some(not_sent);
+                    return Ok(Async::NotReady);
+                }
+            }
+            Ok::<_, Error>(Async::Ready(s.take()))
+        }))
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8 + 16);
+
+    rt.block_on(poll_fn(move || s.close())).unwrap();
+    assert_eq!(rt.block_on(r.collect()).unwrap(), vec![1, 2]);
+}
+
+#[test]
+fn unsealed_file_before_sealed_one_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+
+    // program killed without closing the sender, then a sealed file was added after it.
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    drop(rt.block_on(s.send("item 0".to_string())).unwrap());
+    std::fs::copy(dir.path().join("0"), dir.path().join("1")).unwrap();
+    let mut permissions = std::fs::metadata(dir.path().join("1"))
+        .unwrap()
+        .permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(dir.path().join("1"), permissions).unwrap();
+
+    let err = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5)
+        .err()
+        .unwrap();
+    match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::Layout { path, .. }) => assert_eq!(path, &dir.path().join("0")),
+        other => panic!("Expected layout error, got {:?}", other),
+    }
+}
+
+#[test]
+fn dir_reciver_skips_items_using_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..200).map(|i| format!("item {}", i)).collect();
+
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 150).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+    assert!(dir.path().join("0.idx").exists());
+
+    assert_eq!(r.skip_items(170).unwrap(), 170);
+    // first file was skipped entirely
+    assert!(!dir.path().join("0").exists());
+    assert!(!dir.path().join("0.idx").exists());
+
+    let readed = rt.block_on(r.take(30).collect()).unwrap();
+    assert_eq!(readed, items[170..].to_vec());
+}
+
+#[test]
+fn inspect_dir_keeps_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..7).map(|i| format!("item {}", i)).collect();
+
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    for _ in 0..2 {
+        let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+        let readed = rt.block_on(r.take(items.len() as u64).collect()).unwrap();
+        assert_eq!(readed, items);
+        assert!(dir.path().join("0").exists());
+        assert!(dir.path().join("1").exists());
+    }
+}
+
+#[test]
+fn inspect_read_only_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..7).map(|i| format!("item {}", i)).collect();
+
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+    drop(r);
+    std::fs::remove_file(dir.path().join(".lock")).unwrap();
+    std::fs::write(dir.path().join("3.tmp"), b"half written").unwrap();
+    let set_readonly = |readonly| {
+        let mut permissions = std::fs::metadata(dir.path()).unwrap().permissions();
+        permissions.set_readonly(readonly);
+        std::fs::set_permissions(dir.path(), permissions).unwrap();
+    };
+    let list = || {
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+
+    set_readonly(true);
+    let before = list();
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    let readed = rt.block_on(r.collect());
+    let after = list();
+    set_readonly(false);
+
+    assert_eq!(readed.unwrap(), items);
+    assert_eq!(after, before);
+}
+
+#[test]
+fn dir_reciver_archives_read_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive_dir = dir.path().join("archive");
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..7).map(|i| format!("item {}", i)).collect();
+
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 2).unwrap();
+    r.set_archive(Archive::new(archive_dir.clone()).max_files(2))
+        .unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    let readed = rt.block_on(r.take(items.len() as u64).collect()).unwrap();
+    assert_eq!(readed, items);
+    assert!(!dir.path().join("0").exists());
+
+    // 3 files were fully read, only 2 newest are kept
+    let mut archived: Vec<String> = std::fs::read_dir(&archive_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    archived.sort_by_key(|name| name.rsplit('-').next().unwrap().to_string());
+    assert_eq!(archived.len(), 2);
+    assert!(archived[0].ends_with("-1"));
+    assert!(archived[1].ends_with("-2"));
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_reciver_archives_to_another_file_system() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let archive_dir = match tempfile::tempdir_in("/dev/shm") {
+        Ok(archive_dir) => archive_dir,
+        Err(_) => return,
+    };
+    let dev = |path: &std::path::Path| std::fs::metadata(path).unwrap().dev();
+    if dev(dir.path()) == dev(archive_dir.path()) {
+        return;
+    }
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..4).map(|i| format!("item {}", i)).collect();
+
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 2).unwrap();
+    r.set_archive(Archive::new(archive_dir.path().to_path_buf()))
+        .unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, items);
+    assert!(!dir.path().join("0").exists());
+
+    let mut archived: Vec<String> = std::fs::read_dir(archive_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    archived.sort_by_key(|name| name.rsplit('-').next().unwrap().to_string());
+    assert_eq!(archived.len(), 2);
+    assert!(archived[0].ends_with("-0"));
+    assert!(archived[1].ends_with("-1"));
+}
+
+#[cfg(not(feature = "compression"))]
+#[test]
+fn compression_needs_feature() {
+    let err = Archive::new("archive".into()).compress(true).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    assert!(Archive::new("archive".into()).compress(false).is_ok());
+}
+
+#[test]
+fn orphaned_temp_files_are_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("3.tmp"), b"half written").unwrap();
+
+    let (_s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    assert!(!dir.path().join("3.tmp").exists());
+    assert!(dir.path().join("0").exists());
+}
+
+#[test]
+fn temp_files_of_other_users_are_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    // file another sender is creating right now
+    std::fs::write(dir.path().join("3.tmp"), b"TFSQ").unwrap();
+
+    let (_s2, _r2) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 5).unwrap();
+    assert!(dir.path().join("3.tmp").exists());
+}
+
+#[test]
+fn files_appear_with_complete_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let (s, _r) = unordered_dir_fs::<u32>(dir.path().to_path_buf(), 1).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    drop(rt.block_on(s.send_all(iter_ok::<_, Error>(0..3))).unwrap());
+
+    for index in 0..3 {
+        let content = std::fs::read(dir.path().join(index.to_string())).unwrap();
+        assert_eq!(&content[..8], b"TFSQ\0\0\0\x01");
+    }
+    let names: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(names
+        .iter()
+        .all(|name| !name.to_string_lossy().ends_with(".tmp")));
+}
+
+#[test]
+fn decode_error_reports_path_and_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let mut bytes = b"TFSQ\0\0\0\x01".to_vec();
+    // second item is a string with invalid utf-8
+    for payload in &[b'a', 0xff] {
+        bytes.extend_from_slice(&17u32.to_be_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.push(*payload);
+    }
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    let (item, r) = rt
+        .block_on(r.into_future())
+        .map_err(|(err, _)| err)
+        .unwrap();
+    assert_eq!(item, Some("a".to_string()));
+
+    match rt.block_on(r.into_future()) {
+        Err((
+            Error::Decode {
+                path: err_path,
+                offset,
+                ..
+            },
+            _,
+        )) => {
+            assert_eq!(err_path, path);
+            assert_eq!(offset, 21);
+        }
+        other => panic!(
+            "Expected decode error, got {:?}",
+            other.map(|(item, _)| item).map_err(|(err, _)| err)
+        ),
+    }
+}
+
+#[test]
+fn broken_item_size_is_layout_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let mut bytes = b"TFSQ\0\0\0\x01".to_vec();
+    bytes.extend_from_slice(&17u32.to_be_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.push(b'a');
+    // size of the second item was overwritten
+    bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+    bytes.extend_from_slice(&[0; 16]);
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    match rt.block_on(r.collect()) {
+        Err(Error::Layout {
+            path: err_path,
+            reason,
+        }) => {
+            assert_eq!(err_path, path);
+            assert!(reason.contains("offset 21"), "{}", reason);
+        }
+        other => panic!("Expected layout error, got {:?}", other),
+    }
+}
+
+// File written before items had a header and time when they were written.
+fn write_old_file(path: &std::path::Path) {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&9u32.to_be_bytes());
+    bytes.extend_from_slice(&1u64.to_le_bytes());
+    bytes.push(b'a');
+    std::fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn files_without_header_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    write_old_file(&dir.path().join("0"));
+
+    let err = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::Layout { path, .. }) => assert_eq!(path, &dir.path().join("0")),
+        other => panic!("Expected layout error, got {:?}", other),
+    }
+
+    // next file of the reciver
+    let dir = tempfile::tempdir().unwrap();
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    // send_all closes the sender, so the file is sealed.
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(vec!["a".to_string()])))
+            .unwrap(),
+    );
+    write_old_file(&dir.path().join("1"));
+    match rt.block_on(r.collect()) {
+        Err(Error::Layout { path, .. }) => assert_eq!(path, dir.path().join("1")),
+        other => panic!("Expected layout error, got {:?}", other),
+    }
+}
+
+#[test]
+fn dir_reciver_yields_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let (s, r) = unordered_dir_fs::<u32>(dir.path().to_path_buf(), 100).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    drop(rt.block_on(s.send_all(iter_ok::<_, Error>(0..7))).unwrap());
+
+    let batches = rt.block_on(r.batches(Batch::new(3)).collect()).unwrap();
+    assert_eq!(batches, vec![vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+}
+
+#[test]
+fn raw_dir_stores_bytes_readable_as_items() {
+    let dir = tempfile::tempdir().unwrap();
+    let (s, r) = raw_dir_fs(dir.path().to_path_buf(), 2).unwrap();
+    let items: Vec<Bytes> = (0..5u32)
+        .map(|i| Bytes::from(bincode::serialize(&format!("item {}", i)).unwrap()))
+        .collect();
+
+    let mut rt = Runtime::new().unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(items.clone())))
+            .unwrap(),
+    );
+
+    let typed = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    let readed = rt.block_on(typed.take(5).collect()).unwrap();
+    assert_eq!(readed[4], "item 4");
+
+    let readed = rt.block_on(r.take(5).collect()).unwrap();
+    assert_eq!(readed, items);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn preallocated_files_are_truncated_when_sealed() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let allocated = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().blocks() * 512;
+    let (s, r) = unordered_dir_fs::<u32>(dir.path().to_path_buf(), 2).unwrap();
+    let s = s.preallocate(1024 * 1024).unwrap();
+    if allocated("0") < 1024 * 1024 {
+        // fallocate is not supported by this file system (EOPNOTSUPP), it's ignored.
+        return;
+    }
+    // only the header
+    assert_eq!(std::fs::metadata(dir.path().join("0")).unwrap().len(), 8);
+
+    let mut rt = Runtime::new().unwrap();
+    drop(rt.block_on(s.send_all(iter_ok::<_, Error>(0..3))).unwrap());
+    assert!(allocated("0") < 1024 * 1024);
+    assert!(allocated("1") < 1024 * 1024);
+    assert_eq!(rt.block_on(r.take(3).collect()).unwrap(), vec![0, 1, 2]);
+
+    // disk can't hold such file
+    let dir = tempfile::tempdir().unwrap();
+    let (s, _r) = unordered_dir_fs::<u32>(dir.path().to_path_buf(), 2).unwrap();
+    assert!(s.preallocate(1 << 50).is_err());
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn io_uring_backend_reads_and_writes_dir() {
+    use std::sync::Arc;
+    use tokio_fs_stream::channel::{Backend, IoUring};
+
+    let dir = tempfile::tempdir().unwrap();
+    let backend: Arc<dyn Backend> = Arc::new(IoUring::new(64));
+    let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 3).unwrap();
+    let s = s.backend(backend.clone()).unwrap();
+    r.set_backend(backend).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(items.clone())))
+            .unwrap(),
+    );
+    let readed = rt.block_on(r.take(items.len() as u64).collect()).unwrap();
+    assert_eq!(readed, items);
+    assert!(!dir.path().join("0").exists());
+}
+
+#[test]
+fn memory_store_keeps_items_without_files() {
+    let store = Arc::new(MemoryStore::new());
+    let (s, r) = unordered_store::<String>(store.clone(), 2).unwrap();
+    let data: Vec<String> = (0..5).map(|i| format!("item {}", i)).collect();
+
+    // reciver waits for items sent after it started.
+    let mut rt = Runtime::new().unwrap();
+    let (_, readed) = rt
+        .block_on(
+            s.send_all(iter_ok::<_, Error>(data.clone()))
+                .join(r.collect()),
+        )
+        .unwrap();
+
+    assert_eq!(readed, data);
+    assert_eq!(store.list().unwrap(), Vec::<usize>::new());
+}
+
+#[test]
+fn memory_store_rejects_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_s, mut r) = unordered_store::<String>(Arc::new(MemoryStore::new()), 2).unwrap();
+    let err = r
+        .set_archive(Archive::new(dir.path().to_path_buf()))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn watcher_errors_are_typed() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = LocalFs::new(dir.path().to_path_buf()).unwrap();
+    // segment doesn't exist, so it can't be watched.
+    let err = store.watch(5).err().unwrap();
+    match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::Watcher { .. }) => (),
+        other => panic!("Expected watcher error, got {:?}", other),
+    }
+}
@@ -0,0 +1,72 @@
+use futures::future::{self, poll_fn};
+use futures::stream;
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{unbounded_file, Error, GroupCommit};
+
+#[test]
+fn group_commit_writes_items_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let (s, r) = unbounded_file::<u32>(path.clone()).unwrap();
+    let s = s.group_commit(
+        GroupCommit::new(1024)
+            .linger(Duration::from_millis(50))
+            .fsync(true),
+    );
+
+    let mut rt = Runtime::new().unwrap();
+    let s = rt
+        .block_on(future::lazy(move || {
+            let mut s = s;
+            assert!(s.start_send(1).unwrap().is_ready());
+            assert!(s.poll_complete().unwrap().is_not_ready());
+            Ok::<_, Error>(s)
+        }))
+        .unwrap();
+    // only the header
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8);
+
+    // size prefix, time and u32
+    let mut s = rt.block_on(s.send(2)).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8 + 2 * 16);
+
+    rt.block_on(poll_fn(move || s.close())).unwrap();
+    assert_eq!(rt.block_on(r.collect()).unwrap(), vec![1, 2]);
+}
+
+#[test]
+fn user_file_gets_no_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.log");
+    let other_index = dir.path().join("foo.idx");
+    std::fs::write(&other_index, b"not an index").unwrap();
+
+    let (s, r) = unbounded_file::<u32>(path.clone()).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    // send_all closes the sender.
+    drop(
+        rt.block_on(s.send_all(stream::iter_ok::<_, Error>(0..100)))
+            .unwrap(),
+    );
+    assert_eq!(
+        rt.block_on(r.collect()).unwrap(),
+        (0..100).collect::<Vec<_>>()
+    );
+
+    assert_eq!(std::fs::read(&other_index).unwrap(), b"not an index");
+    assert!(!dir.path().join("foo.log.idx").exists());
+}
+
+#[test]
+fn user_file_with_incomplete_item_is_not_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.log");
+    let content = b"TFSQ\0\0\0\x01\0\0\0\x10half";
+    std::fs::write(&path, content).unwrap();
+
+    let err = unbounded_file::<u32>(path.clone()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&path).unwrap(), content);
+}
@@ -0,0 +1,134 @@
+use futures::stream::iter_ok;
+use std::io;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{
+    compact, list_segments, purge, raw_records, unordered_dir_fs, verify_segment, Error,
+};
+
+#[test]
+fn inspect_segments_without_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..5).map(|i| format!("item {}", i)).collect();
+
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 3).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    let segments = list_segments(dir.path()).unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].items, 3);
+    assert_eq!(segments[1].items, 2);
+    assert!(segments.iter().all(|segment| segment.sealed));
+
+    let readed: Vec<String> = segments
+        .iter()
+        .flat_map(|segment| raw_records(&segment.path).unwrap())
+        .map(|record| bincode::deserialize(&record.unwrap().payload).unwrap())
+        .collect();
+    assert_eq!(readed, items);
+
+    for segment in &segments {
+        assert!(verify_segment(&segment.path).unwrap().is_ok());
+    }
+    assert_eq!(list_segments(dir.path()).unwrap(), segments);
+}
+
+#[test]
+fn compact_rewrites_items_into_fewer_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..5).map(|i| format!("item {}", i)).collect();
+
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    // reciver still holds the dir
+    let err = compact(dir.path(), 10, None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    drop(r);
+
+    let report = compact(dir.path(), 10, None).unwrap();
+    assert_eq!(report.files_removed, 5);
+    assert_eq!(report.files_created, 1);
+    assert_eq!(report.items, 5);
+
+    let segments = list_segments(dir.path()).unwrap();
+    assert_eq!(segments.len(), 1);
+    assert!(segments[0].sealed);
+    assert!(verify_segment(&segments[0].path).unwrap().is_ok());
+
+    // new file is sealed, the sender continues after it.
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(vec!["item 5".to_string()])))
+            .unwrap(),
+    );
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, [items, vec!["item 5".to_string()]].concat());
+}
+
+#[test]
+fn purge_removes_sealed_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..5).map(|i| format!("item {}", i)).collect();
+
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 3).unwrap();
+    let send = iter_ok::<_, ()>(items).fold(s, |s, item| s.send(item).map_err(drop));
+    let s = rt.block_on(send).unwrap();
+
+    // second file is still open, only the first one is removed
+    let removed = purge(dir.path(), false).unwrap();
+    assert_eq!(removed, vec![dir.path().join("0")]);
+    assert!(dir.path().join("1").exists());
+
+    let err = purge(dir.path(), true).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    drop((s, r));
+
+    let removed = purge(dir.path(), true).unwrap();
+    assert_eq!(removed, vec![dir.path().join("1")]);
+    assert!(list_segments(dir.path()).unwrap().is_empty());
+}
+
+#[test]
+fn broken_item_size_is_layout_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let payload = bincode::serialize("item 0").unwrap();
+    let mut bytes = b"TFSQ".to_vec();
+    bytes.extend_from_slice(&1u32.to_be_bytes());
+    bytes.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    // size of the next item was overwritten
+    bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+    bytes.extend_from_slice(&[0; 16]);
+    std::fs::write(&path, bytes).unwrap();
+
+    let mut records = raw_records(&path).unwrap();
+    assert_eq!(records.next().unwrap().unwrap().payload, payload);
+    let err = records.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    match err.get_ref().and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::Layout {
+            path: err_path,
+            reason,
+        }) => {
+            assert_eq!(err_path, &path);
+            assert!(reason.contains("offset 26"), "{}", reason);
+        }
+        other => panic!("Expected layout error, got {:?}", other),
+    }
+    assert!(records.next().is_none());
+
+    let err = verify_segment(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
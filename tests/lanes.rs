@@ -0,0 +1,269 @@
+use futures::stream::{iter_ok, poll_fn};
+use futures::{AsyncSink, StartSend};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+use tokio_fs_stream::channel::{
+    unordered_dir_fs, Error, Eviction, Lane, LaneScheduling, SendAllFsErr,
+};
+use tokio_fs_stream::SinkFsExt;
+
+// Sink that never accepts any item and never notifies it is ready.
+struct DeadSink;
+
+impl Sink for DeadSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        Ok(AsyncSink::NotReady(item))
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::NotReady)
+    }
+}
+
+// Sink that doesn't accept first `rejects` items.
+struct GateSink {
+    rejects: usize,
+    items: Vec<String>,
+}
+
+impl Sink for GateSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.rejects > 0 {
+            self.rejects -= 1;
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+fn strings(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("item {}", i)).collect()
+}
+
+/// Send `items` through a single lane with `quota` to a sink that doesn't accept any of them
+/// while they come from the stream.
+fn send_over_quota(quota: usize, eviction: Eviction, items: Vec<String>) -> Vec<String> {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let sink = GateSink {
+        rejects: items.len(),
+        items: Vec::new(),
+    };
+    let lanes = vec![Lane::new(dir.path().to_path_buf()).quota(quota, eviction)];
+    let send_all = sink
+        .send_all_fs_lanes(
+            iter_ok::<_, io::Error>(items),
+            lanes,
+            LaneScheduling::Priority,
+            |_item| 0,
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+    sink.items
+}
+
+fn store_backlog(rt: &mut Runtime, dir: &Path, items: &[&str]) {
+    let (s, _r) = unordered_dir_fs::<String>(dir.to_path_buf(), 1000).unwrap();
+    let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+    let send_backlog = iter_ok::<_, ()>(items).fold(s, |s, item| s.send(item).map_err(drop));
+    drop(rt.block_on(send_backlog).unwrap());
+}
+
+#[test]
+fn lanes_drained_by_priority() {
+    let critical = tempfile::tempdir().unwrap();
+    let best_effort = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    store_backlog(&mut rt, best_effort.path(), &["b0", "b1"]);
+    store_backlog(&mut rt, critical.path(), &["c0", "c1"]);
+
+    let lanes = vec![
+        Lane::new(critical.path().to_path_buf()),
+        Lane::new(best_effort.path().to_path_buf()),
+    ];
+    let send_all = Vec::<String>::new()
+        .send_all_fs_lanes(
+            iter_ok::<_, ()>(vec![]),
+            lanes,
+            LaneScheduling::Priority,
+            |_item| 0,
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink, vec!["c0", "c1", "b0", "b1"]);
+}
+
+#[test]
+fn lanes_drained_by_weight() {
+    let heavy = tempfile::tempdir().unwrap();
+    let light = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    store_backlog(&mut rt, heavy.path(), &["h0", "h1", "h2", "h3"]);
+    store_backlog(&mut rt, light.path(), &["l0", "l1", "l2"]);
+
+    let lanes = vec![
+        Lane::new(heavy.path().to_path_buf()).weight(2),
+        Lane::new(light.path().to_path_buf()),
+    ];
+    let send_all = Vec::<String>::new()
+        .send_all_fs_lanes(
+            iter_ok::<_, ()>(vec![]),
+            lanes,
+            LaneScheduling::Weighted,
+            |_item| 0,
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink, vec!["h0", "h1", "l0", "h2", "h3", "l1", "l2"]);
+}
+
+#[test]
+fn full_lane_fails_with_quota_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..3).map(|i| format!("item {}", i)).collect();
+
+    let lanes = vec![Lane::new(dir.path().to_path_buf()).quota(2, Eviction::Fail)];
+    let send_all = DeadSink
+        .send_all_fs_lanes(
+            iter_ok::<_, io::Error>(items),
+            lanes,
+            LaneScheduling::Priority,
+            |_item| 0,
+        )
+        .unwrap();
+    match rt.block_on(send_all).map_err(|failure| failure.error) {
+        Err(SendAllFsErr::StoreError {
+            source: Error::Quota { path, max_items },
+        }) => {
+            assert_eq!(path, dir.path());
+            assert_eq!(max_items, 2);
+        }
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("lane over quota is not reported"),
+    }
+}
+
+#[test]
+fn full_lane_drops_newest_items() {
+    let sent = send_over_quota(2, Eviction::DropNewest, strings(5));
+    assert_eq!(sent, strings(2));
+}
+
+#[test]
+fn full_lane_drops_oldest_items() {
+    let sent = send_over_quota(2, Eviction::DropOldest, strings(5));
+    assert_eq!(sent, strings(5)[3..].to_vec());
+}
+
+#[test]
+fn full_lane_blocks_stream() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items = strings(10);
+
+    let lanes = vec![Lane::new(dir.path().to_path_buf()).quota(2, Eviction::Block)];
+    let mut send_all = DeadSink
+        .send_all_fs_lanes(
+            iter_ok::<_, io::Error>(items.clone()),
+            lanes,
+            LaneScheduling::Priority,
+            |_item| 0,
+        )
+        .unwrap();
+    let handle = send_all.shutdown_handle();
+    let shutdown = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        handle.shutdown();
+    });
+    let (_sink, stream) = rt.block_on(send_all).unwrap();
+    shutdown.join().unwrap();
+
+    // 2 items over quota: the one read from the lane that sink didn't accept and the one waiting
+    // for the lane that is written on shutdown. The rest is left in the stream.
+    let (_s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let mut stored = rt.block_on(r.collect()).unwrap();
+    assert!(stored.len() <= 4);
+    stored.extend(rt.block_on(stream.collect()).unwrap());
+    assert_eq!(stored, items);
+}
+
+#[test]
+fn backlog_counts_to_quota() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    // one item is read from the lane and kept for the sink, 2 are left.
+    store_backlog(
+        &mut rt,
+        dir.path(),
+        &["backlog 0", "backlog 1", "backlog 2"],
+    );
+
+    let lanes = vec![Lane::new(dir.path().to_path_buf()).quota(2, Eviction::Fail)];
+    let send_all = DeadSink
+        .send_all_fs_lanes(
+            iter_ok::<_, io::Error>(strings(1)),
+            lanes,
+            LaneScheduling::Priority,
+            |_item| 0,
+        )
+        .unwrap();
+    let send_all = Timeout::new(send_all, Duration::from_secs(5));
+    match rt
+        .block_on(send_all)
+        .map_err(|err| err.into_inner().unwrap().error)
+    {
+        Err(SendAllFsErr::StoreError {
+            source: Error::Quota { max_items, .. },
+        }) => assert_eq!(max_items, 2),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("lane over quota is not reported"),
+    }
+}
+
+#[test]
+fn oldest_items_are_removed_while_sink_is_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    // reciver reads the backlog first and waits for the sink, so it doesn't read the lane.
+    store_backlog(&mut rt, dir.path(), &["backlog"]);
+    let items = strings(10);
+    // stream that never ends
+    let stream = iter_ok::<_, io::Error>(items.clone()).chain(poll_fn(|| Ok(Async::NotReady)));
+
+    let lanes = vec![Lane::new(dir.path().to_path_buf())
+        .max_items_in_file(1)
+        .quota(2, Eviction::DropOldest)];
+    let mut send_all = DeadSink
+        .send_all_fs_lanes(stream, lanes, LaneScheduling::Priority, |_item| 0)
+        .unwrap();
+    let handle = send_all.shutdown_handle();
+    let shutdown = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        handle.shutdown();
+    });
+    let (_sink, _stream) = rt.block_on(send_all).unwrap();
+    shutdown.join().unwrap();
+
+    // only the newest items are left, files of the evicted ones are removed.
+    let (_s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let stored = rt.block_on(r.collect()).unwrap();
+    assert_eq!(stored, ["item 8", "item 9"]);
+}
@@ -0,0 +1,102 @@
+#![cfg(all(unix, feature = "mmap"))]
+
+use futures::stream::iter_ok;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{unbounded_file, unordered_dir_fs};
+
+fn set_readonly(path: &std::path::Path) {
+    let mut permissions = std::fs::metadata(path).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(path, permissions).unwrap();
+}
+
+fn frame(item: &str) -> Vec<u8> {
+    let written_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let payload = bincode::serialize(item).unwrap();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&written_at.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+#[test]
+fn sealed_files_are_read_from_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 3).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+    assert!(std::fs::metadata(dir.path().join("0"))
+        .unwrap()
+        .permissions()
+        .readonly());
+
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, items);
+}
+
+#[test]
+fn skip_items_into_mapped_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+
+    let (s, mut r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 4).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+
+    // item 5 is in the middle of the second file
+    assert_eq!(r.skip_items(5).unwrap(), 5);
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, items[5..].to_vec());
+}
+
+#[test]
+fn mapped_file_stops_at_partial_frame() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let mut bytes = b"TFSQ".to_vec();
+    bytes.extend_from_slice(&1u32.to_be_bytes());
+    bytes.extend(frame("item 0"));
+    bytes.extend(frame("item 1"));
+    let partial = frame("item 2");
+    bytes.extend_from_slice(&partial[..partial.len() - 3]);
+    std::fs::write(&path, bytes).unwrap();
+    set_readonly(&path);
+
+    let (_s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 10).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, vec!["item 0".to_string(), "item 1".to_string()]);
+}
+
+#[test]
+fn sealed_single_file_is_read_without_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let items: Vec<u32> = (0..5).collect();
+
+    let (s, r) = unbounded_file::<u32>(path.clone()).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(items.clone())))
+            .unwrap(),
+    );
+    set_readonly(&path);
+
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, items);
+}
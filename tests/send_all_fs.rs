@@ -0,0 +1,847 @@
+use futures::stream::{iter_ok, poll_fn};
+use futures::{task, AsyncSink, StartSend};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Timeout};
+use tokio_fs_stream::channel::{
+    inspect_dir_fs, list_segments, raw_records, reinject_dead_letters, unordered_dir_fs,
+    unordered_store, Backoff, Batch, CircuitBreaker, DeadLetter, DrainPolicy, Error, MemoryStore,
+    RateLimit, RetryError, RetryPolicy, SendAllFsErr, StorePolicy, DEAD_LETTER_DIR,
+};
+use tokio_fs_stream::SinkFsExt;
+
+// Sink that never accepts any item and never notifies it is ready.
+struct DeadSink;
+
+impl Sink for DeadSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        Ok(AsyncSink::NotReady(item))
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::NotReady)
+    }
+}
+
+#[test]
+fn shutdown_spills_items_to_dir() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let data = vec![
+        "Ala ma kota".to_string(),
+        "Kot ma ale".to_string(),
+        "你好".to_string(),
+    ];
+    // stream that never ends
+    let stream = iter_ok::<_, io::Error>(data.clone()).chain(poll_fn(|| Ok(Async::NotReady)));
+
+    let mut send_all = DeadSink
+        .send_all_fs_backpresure(stream, dir.path().to_path_buf())
+        .unwrap();
+    let handle = send_all.shutdown_handle();
+
+    let mut rt = Runtime::new().unwrap();
+    rt.spawn(
+        Delay::new(Instant::now() + Duration::from_millis(100))
+            .map(move |_| handle.shutdown())
+            .map_err(drop),
+    );
+    let (_sink, _stream) = rt.block_on(send_all).unwrap();
+
+    let (_s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let readed = rt.block_on(r.collect()).unwrap();
+    assert_eq!(readed, data);
+}
+
+#[test]
+fn backlog_first_keeps_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+
+    // backlog left by previous run
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let send_backlog = s
+        .send("backlog 1".to_string())
+        .and_then(|s| s.send("backlog 2".to_string()));
+    drop(rt.block_on(send_backlog).unwrap());
+
+    let stream = iter_ok::<_, ()>(vec!["live 1".to_string(), "live 2".to_string()]);
+    let send_all = Vec::new()
+        .send_all_fs_backpresure(stream, dir.path().to_path_buf())
+        .unwrap()
+        .drain_policy(DrainPolicy::BacklogFirst);
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink, vec!["backlog 1", "backlog 2", "live 1", "live 2"]);
+}
+
+#[test]
+fn backlog_in_memory_store_is_sent_first() {
+    let store = Arc::new(MemoryStore::new());
+    let mut rt = Runtime::new().unwrap();
+
+    let (s, _r) = unordered_store::<String>(store.clone(), 1000).unwrap();
+    let send_backlog = s
+        .send("backlog 1".to_string())
+        .and_then(|s| s.send("backlog 2".to_string()));
+    drop(rt.block_on(send_backlog).unwrap());
+
+    let stream = iter_ok::<_, ()>(vec!["live 1".to_string(), "live 2".to_string()]);
+    let send_all = Vec::new()
+        .send_all_store_backpresure(stream, store)
+        .unwrap()
+        .drain_policy(DrainPolicy::BacklogFirst);
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink, vec!["backlog 1", "backlog 2", "live 1", "live 2"]);
+}
+
+#[test]
+fn replay_is_rate_limited() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+
+    let backlog: Vec<String> = (0..10).map(|i| format!("backlog {}", i)).collect();
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let send_backlog =
+        iter_ok::<_, ()>(backlog.clone()).fold(s, |s, item| s.send(item).map_err(drop));
+    drop(rt.block_on(send_backlog).unwrap());
+
+    let started = Instant::now();
+    let send_all = Vec::<String>::new()
+        .send_all_fs_backpresure(iter_ok::<_, ()>(vec![]), dir.path().to_path_buf())
+        .unwrap()
+        .replay_rate_limit(RateLimit::items_per_sec(20).burst(1));
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink, backlog);
+    assert!(started.elapsed() >= Duration::from_millis(400));
+}
+
+// Sealed file with item "a" followed by a string with invalid utf-8.
+fn write_broken_file(path: &Path) {
+    let mut bytes = b"TFSQ\0\0\0\x01".to_vec();
+    for payload in &[b'a', 0xff] {
+        bytes.extend_from_slice(&17u32.to_be_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.push(*payload);
+    }
+    std::fs::write(path, bytes).unwrap();
+    let mut perms = std::fs::metadata(path).unwrap().permissions();
+    perms.set_readonly(true);
+    std::fs::set_permissions(path, perms).unwrap();
+}
+
+#[test]
+fn store_error_returns_sink_and_stream() {
+    let dir = tempfile::tempdir().unwrap();
+    write_broken_file(&dir.path().join("0"));
+
+    let mut rt = Runtime::new().unwrap();
+    let send_all = Vec::<String>::new()
+        .send_all_fs_backpresure(
+            iter_ok::<_, ()>(vec!["live".to_string()]),
+            dir.path().to_path_buf(),
+        )
+        .unwrap();
+    let failure = rt.block_on(send_all).unwrap_err();
+
+    match failure.error {
+        SendAllFsErr::StoreError {
+            source: Error::Decode { offset, .. },
+        } => assert_eq!(offset, 21),
+        ref err => panic!("Expected decode error, got {:?}", err),
+    }
+    assert_eq!(failure.sink, vec!["a".to_string()]);
+    let rest = rt.block_on(failure.stream.collect()).unwrap();
+    assert_eq!(rest, vec!["live".to_string()]);
+}
+
+#[test]
+fn broken_file_is_quarantined() {
+    let dir = tempfile::tempdir().unwrap();
+    write_broken_file(&dir.path().join("0"));
+    let mut rt = Runtime::new().unwrap();
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    drop(rt.block_on(s.send_all(iter_ok::<_, io::Error>(vec!["stored".to_string()]))));
+
+    let send_all = Vec::<String>::new()
+        .send_all_fs_backpresure(
+            iter_ok::<_, ()>(vec!["live".to_string()]),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .store_error_handler(|_attempt: usize, err: &Error| match err {
+            Error::Decode { .. } => StorePolicy::Quarantine,
+            _ => StorePolicy::Fail,
+        });
+    let (mut sink, _stream) = rt.block_on(send_all).unwrap();
+
+    sink.sort();
+    assert_eq!(sink, vec!["a", "live", "stored"]);
+    let quarantined = std::fs::read_dir(dir.path().join("quarantine")).unwrap();
+    assert_eq!(quarantined.count(), 1);
+}
+
+// Sink that fails every other item.
+#[derive(Default)]
+struct FlakySink {
+    calls: usize,
+    items: Vec<String>,
+}
+
+impl Sink for FlakySink {
+    type SinkItem = String;
+    type SinkError = String;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.calls += 1;
+        if self.calls % 2 == 0 {
+            return Err("connection reset".to_string());
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn transient_sink_errors_are_spilled() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..6).map(|i| format!("item {}", i)).collect();
+
+    let backoff = Backoff::exponential(Duration::from_millis(1), Duration::from_millis(10));
+    let send_all = FlakySink::default()
+        .send_all_fs_backpresure(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .spill_on_sink_error(|err: &String| err == "connection reset", backoff);
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    let mut sent = sink.items;
+    sent.sort();
+    assert_eq!(sent, items);
+}
+
+// Sink that is not ready only for the `busy_at` item.
+struct BusyOnceSink {
+    calls: usize,
+    busy_at: usize,
+    items: Vec<String>,
+}
+
+impl Sink for BusyOnceSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.calls += 1;
+        if self.calls == self.busy_at {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn items_spilled_after_sealed_tail_are_sent() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let backlog: Vec<String> = (0..3).map(|i| format!("backlog {}", i)).collect();
+    let items: Vec<String> = (0..6).map(|i| format!("item {}", i)).collect();
+
+    // previous run closed the sender, so the last file is sealed.
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, io::Error>(backlog.clone())))
+            .unwrap(),
+    );
+    assert!(std::fs::metadata(dir.path().join("0"))
+        .unwrap()
+        .permissions()
+        .readonly());
+
+    // backlog is replayed to the end of the dir, then the first live item is spilled.
+    let sink = BusyOnceSink {
+        calls: 0,
+        busy_at: backlog.len() + 1,
+        items: Vec::new(),
+    };
+    let send_all = sink
+        .send_all_fs_backpresure(
+            iter_ok::<_, io::Error>(items.clone()),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .drain_policy(DrainPolicy::BacklogFirst);
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink.items, [backlog, items].concat());
+    assert!(list_segments(dir.path()).unwrap().is_empty());
+}
+
+// Sink that fails the first `failures` items.
+#[derive(Default)]
+struct OutageSink {
+    failures: usize,
+    items: Vec<String>,
+}
+
+impl Sink for OutageSink {
+    type SinkItem = String;
+    type SinkError = String;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err("service unavailable".to_string());
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn exhausted_retries_are_spilled() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..4).map(|i| format!("item {}", i)).collect();
+
+    let backoff =
+        Backoff::exponential(Duration::from_millis(1), Duration::from_millis(10)).jitter(0.5);
+    let policy = RetryPolicy::new(backoff)
+        .max_attempts(2)
+        .retry_if(|err: &String| err == "service unavailable");
+    let sink = OutageSink {
+        failures: 5,
+        items: Vec::new(),
+    };
+    let send_all = sink
+        .send_all_fs_retry(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+            policy,
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    // items that failed during the final drain are left for the next start, the file they were
+    // replayed from is not finished, so they are there twice.
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    let mut sent = sink.into_inner().items;
+    sent.extend(rt.block_on(r.collect()).unwrap());
+    sent.sort();
+    sent.dedup();
+    assert_eq!(sent, items);
+}
+
+// Sink that fails every item the first time it's sent, the error is the item.
+#[derive(Default)]
+struct FailFirstSink {
+    failed: HashSet<String>,
+    items: Vec<String>,
+}
+
+impl Sink for FailFirstSink {
+    type SinkItem = String;
+    type SinkError = String;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.failed.insert(item.clone()) {
+            return Err(item);
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn failed_items_are_spilled_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..4).map(|i| format!("item {}", i)).collect();
+
+    let send_all = FailFirstSink::default()
+        .send_all_fs_backpresure(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .spill_failed_items(Ok);
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(sink.items, items);
+}
+
+// Sink that is never able to send an item. It's not ready every other time, the error is the
+// item.
+#[derive(Default)]
+struct BrokenSink {
+    ready: bool,
+}
+
+impl Sink for BrokenSink {
+    type SinkItem = String;
+    type SinkError = String;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if !self.ready {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.ready = false;
+        Err(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if self.ready {
+            return Ok(Async::Ready(()));
+        }
+        self.ready = true;
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
+
+#[test]
+fn items_failing_during_drain_are_left_in_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..4).map(|i| format!("item {}", i)).collect();
+
+    let send_all = BrokenSink::default()
+        .send_all_fs_backpresure(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .spill_failed_items(Ok);
+    let (_sink, _stream) = rt
+        .block_on(Timeout::new(send_all, Duration::from_secs(5)))
+        .unwrap();
+
+    // items replayed from a file that is not finished are there twice.
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    let mut stored = rt.block_on(r.collect()).unwrap();
+    stored.sort();
+    stored.dedup();
+    assert_eq!(stored, items);
+}
+
+// Sink that rejects items starting with "bad".
+#[derive(Default)]
+struct ValidatingSink {
+    items: Vec<String>,
+}
+
+impl Sink for ValidatingSink {
+    type SinkItem = String;
+    type SinkError = String;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if item.starts_with("bad") {
+            return Err("400 Bad Request".to_string());
+        }
+        self.items.push(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn rejected_items_are_dead_letters() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items = vec![
+        "good 0".to_string(),
+        "bad".to_string(),
+        "good 1".to_string(),
+    ];
+
+    let policy =
+        RetryPolicy::new(Backoff::default()).retry_if(|err: &String| !err.starts_with("400"));
+    let send_all = ValidatingSink::default()
+        .send_all_fs_retry(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+            policy,
+        )
+        .unwrap()
+        .dead_letters(RetryError::take_rejected)
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+    assert_eq!(sink.into_inner().items, vec!["good 0", "good 1"]);
+
+    let dead_letter_dir = dir.path().join(DEAD_LETTER_DIR);
+    let segments = list_segments(&dead_letter_dir).unwrap();
+    let record = raw_records(&segments[0].path)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let dead_letter: DeadLetter<String> = bincode::deserialize(&record.payload).unwrap();
+    assert_eq!(dead_letter.item, "bad");
+    assert_eq!(dead_letter.error, "400 Bad Request");
+
+    assert_eq!(reinject_dead_letters::<String>(dir.path(), 0).unwrap(), 1);
+    assert!(list_segments(&dead_letter_dir).unwrap().is_empty());
+    let reinjected: u64 = list_segments(dir.path())
+        .unwrap()
+        .iter()
+        .map(|segment| segment.items)
+        .sum();
+    assert_eq!(reinjected, 1);
+}
+
+#[test]
+fn reinject_seals_unsealed_last_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+
+    let dead_letter = DeadLetter {
+        item: "bad".to_string(),
+        error: "400 Bad Request".to_string(),
+        rejected_at: 0,
+    };
+    std::fs::create_dir(dir.path().join(DEAD_LETTER_DIR)).unwrap();
+    let (s, r) = unordered_dir_fs(dir.path().join(DEAD_LETTER_DIR), 1000).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(vec![dead_letter])))
+            .unwrap(),
+    );
+    drop(r);
+    // program killed without closing the sender
+    let (s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    drop(rt.block_on(s.send("good".to_string())).unwrap());
+    drop(r);
+
+    assert_eq!(reinject_dead_letters::<String>(dir.path(), 0).unwrap(), 1);
+    assert!(std::fs::metadata(dir.path().join("0"))
+        .unwrap()
+        .permissions()
+        .readonly());
+    let (_s, r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    let readed = rt
+        .block_on(Timeout::new(r.collect(), Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(readed, vec!["good", "bad"]);
+}
+
+#[test]
+fn dead_letters_keep_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<String> = (0..4).map(|i| format!("bad {}", i)).collect();
+
+    let policy = RetryPolicy::new(Backoff::default()).retry_if(|_: &String| false);
+    let send_all = ValidatingSink::default()
+        .send_all_fs_retry(
+            iter_ok::<_, String>(items.clone()),
+            dir.path().to_path_buf(),
+            policy,
+        )
+        .unwrap()
+        .dead_letters(RetryError::take_rejected)
+        .unwrap();
+    let (_sink, _stream) = rt.block_on(send_all).unwrap();
+
+    let r = inspect_dir_fs::<DeadLetter<String>>(dir.path().join(DEAD_LETTER_DIR)).unwrap();
+    let dead_letters = rt.block_on(r.collect()).unwrap();
+    let rejected: Vec<String> = dead_letters.into_iter().map(|d| d.item).collect();
+    assert_eq!(rejected, items);
+}
+
+// Sink that is not ready until `healthy_at`, records every offered item and if it was accepted.
+struct RecordingSink {
+    healthy_at: Instant,
+    calls: Vec<(Instant, String, bool)>,
+}
+
+impl Sink for RecordingSink {
+    type SinkItem = String;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let now = Instant::now();
+        let healthy = now >= self.healthy_at;
+        self.calls.push((now, item.clone(), healthy));
+        if !healthy {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn circuit_breaker_opens_probes_and_closes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items = strings(0..10);
+    let cooldown = Duration::from_millis(100);
+
+    // 2 items open the breaker, the rest comes while it's open.
+    let late_items = items[2..].to_vec();
+    let late = Delay::new(Instant::now() + Duration::from_millis(50))
+        .map(move |_| iter_ok(late_items))
+        .map_err(|err| io::Error::other(err.to_string()))
+        .flatten_stream();
+    let sink = RecordingSink {
+        healthy_at: Instant::now() + Duration::from_millis(250),
+        calls: Vec::new(),
+    };
+    let send_all = sink
+        .send_all_fs_backpresure(
+            iter_ok(items[..2].to_vec()).chain(late),
+            dir.path().to_path_buf(),
+        )
+        .unwrap()
+        .circuit_breaker(CircuitBreaker::new(2, cooldown));
+
+    let dir_path = dir.path().to_path_buf();
+    let stored_while_open = Delay::new(Instant::now() + Duration::from_millis(80))
+        .map_err(|err| io::Error::other(err.to_string()))
+        .and_then(move |_| list_segments(&dir_path))
+        .map(|segments| segments.iter().map(|segment| segment.items).sum::<u64>());
+    let stored_while_open = futures::sync::oneshot::spawn(stored_while_open, &rt.executor());
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+    let calls = sink.calls;
+
+    // closed: 2 items fail and open the breaker.
+    assert!(!calls[0].2 && !calls[1].2, "{:?}", calls);
+    assert_eq!(
+        (calls[0].1.as_str(), calls[1].1.as_str()),
+        ("item 00", "item 01")
+    );
+    // open: items from the stream go straight to the dir.
+    assert_eq!(rt.block_on(stored_while_open).unwrap(), items.len() as u64);
+    // half open: one item from the dir is a probe after every cooldown until it's accepted.
+    let first_success = calls.iter().position(|call| call.2).unwrap();
+    assert!(first_success >= 3, "{:?}", calls);
+    for pair in calls[1..=first_success].windows(2) {
+        assert!(pair[1].0 - pair[0].0 >= cooldown - Duration::from_millis(5));
+        assert_eq!(pair[1].1, "item 00");
+    }
+    // closed: all other items are sent right away.
+    assert!(calls[first_success..].iter().all(|call| call.2));
+    let sent: Vec<String> = calls[first_success..]
+        .iter()
+        .map(|call| call.1.clone())
+        .collect();
+    assert_eq!(sent, items);
+}
+
+// Sink of batches that is not ready until `healthy_at`.
+struct BulkSink {
+    healthy_at: Instant,
+    batches: Vec<Vec<String>>,
+}
+
+impl Sink for BulkSink {
+    type SinkItem = Vec<String>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, batch: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if Instant::now() < self.healthy_at {
+            futures::task::current().notify();
+            return Ok(AsyncSink::NotReady(batch));
+        }
+        self.batches.push(batch);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl BulkSink {
+    fn healthy() -> Self {
+        BulkSink {
+            healthy_at: Instant::now(),
+            batches: Vec::new(),
+        }
+    }
+}
+
+fn send_batched<U>(stream: U, batch: Batch) -> Vec<Vec<String>>
+where
+    U: Stream<Item = String, Error = io::Error> + Send + 'static,
+{
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let send_all = BulkSink::healthy()
+        .send_all_fs_batched(stream, dir.path().to_path_buf(), batch)
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+    sink.into_inner().batches
+}
+
+fn strings(items: std::ops::Range<usize>) -> Vec<String> {
+    items.map(|i| format!("item {:02}", i)).collect()
+}
+
+#[test]
+fn batches_are_limited_by_max_items() {
+    let batches = send_batched(iter_ok(strings(0..7)), Batch::new(3));
+    assert_eq!(batches, vec![strings(0..3), strings(3..6), strings(6..7)]);
+}
+
+#[test]
+fn batches_are_limited_by_max_bytes() {
+    // every item takes 8 bytes of length and 7 bytes of text.
+    let batches = send_batched(iter_ok(strings(0..5)), Batch::new(100).max_bytes(30));
+    assert_eq!(batches, vec![strings(0..2), strings(2..4), strings(4..5)]);
+}
+
+#[test]
+fn incomplete_batch_is_sent_after_linger() {
+    let late = Delay::new(Instant::now() + Duration::from_millis(300))
+        .map(|_| "item 02".to_string())
+        .map_err(|err| io::Error::other(err.to_string()))
+        .into_stream();
+    let batches = send_batched(
+        iter_ok(strings(0..2)).chain(late),
+        Batch::new(100).linger(Duration::from_millis(50)),
+    );
+    assert_eq!(batches, vec![strings(0..2), strings(2..3)]);
+}
+
+#[test]
+fn items_from_dir_are_sent_in_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items = strings(0..20);
+
+    // backlog left by previous run
+    let (s, _r) = unordered_dir_fs::<String>(dir.path().to_path_buf(), 1000).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(items.clone())))
+            .unwrap(),
+    );
+
+    let send_all = BulkSink::healthy()
+        .send_all_fs_batched(
+            iter_ok::<_, io::Error>(Vec::new()),
+            dir.path().to_path_buf(),
+            Batch::new(8).linger(Duration::from_millis(10)),
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(
+        sink.into_inner().batches,
+        vec![strings(0..8), strings(8..16), strings(16..20)]
+    );
+}
+
+static SERIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+// Item that counts how many times it was serialized.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Counted(String);
+
+impl Serialize for Counted {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SERIALIZED.fetch_add(1, Ordering::SeqCst);
+        self.0.serialize(serializer)
+    }
+}
+
+#[test]
+fn items_from_dir_are_batched_by_stored_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items: Vec<Counted> = strings(0..5).into_iter().map(Counted).collect();
+
+    let (s, _r) = unordered_dir_fs::<Counted>(dir.path().to_path_buf(), 1000).unwrap();
+    drop(
+        rt.block_on(s.send_all(iter_ok::<_, Error>(items.clone())))
+            .unwrap(),
+    );
+    let serialized = SERIALIZED.load(Ordering::SeqCst);
+
+    // every item takes 8 bytes of length and 7 bytes of text.
+    let send_all = Vec::new()
+        .send_all_fs_batched(
+            iter_ok::<Vec<Counted>, ()>(Vec::new()),
+            dir.path().to_path_buf(),
+            Batch::new(100).max_bytes(30),
+        )
+        .unwrap();
+    let (sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert_eq!(
+        sink.into_inner(),
+        vec![
+            items[0..2].to_vec(),
+            items[2..4].to_vec(),
+            items[4..5].to_vec()
+        ]
+    );
+    assert_eq!(SERIALIZED.load(Ordering::SeqCst), serialized);
+}
+
+#[test]
+fn shutdown_spills_incomplete_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let items = strings(0..3);
+    // stream that never ends
+    let stream = iter_ok::<_, io::Error>(items.clone()).chain(poll_fn(|| Ok(Async::NotReady)));
+
+    let mut send_all = BulkSink::healthy()
+        .send_all_fs_batched(
+            stream,
+            dir.path().to_path_buf(),
+            Batch::new(100).linger(Duration::from_secs(3600)),
+        )
+        .unwrap();
+    let handle = send_all.shutdown_handle();
+    rt.spawn(
+        Delay::new(Instant::now() + Duration::from_millis(100))
+            .map(move |_| handle.shutdown())
+            .map_err(drop),
+    );
+    let (mut sink, _stream) = rt.block_on(send_all).unwrap();
+
+    assert!(sink.take_pending().is_empty());
+    assert!(sink.into_inner().batches.is_empty());
+    let r = inspect_dir_fs::<String>(dir.path().to_path_buf()).unwrap();
+    assert_eq!(rt.block_on(r.collect()).unwrap(), items);
+}
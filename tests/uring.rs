@@ -0,0 +1,129 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+use futures::future::join_all;
+use futures::stream::iter_ok;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use tokio_fs_stream::channel::{Backend, IoUring};
+
+// size limit of files written by this process, see `short_write_is_continued`.
+const FILE_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+fn open_file(path: &std::path::Path) -> File {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .unwrap()
+}
+
+#[test]
+fn short_write_is_continued() {
+    let backend = IoUring::new(8);
+    if !backend.is_io_uring() {
+        return;
+    }
+    // Writes crossing the limit are short, the next write fails with EFBIG instead of a signal.
+    unsafe {
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        let limit = libc::rlimit {
+            rlim_cur: FILE_SIZE_LIMIT,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limit), 0);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let mut file = open_file(&path);
+    file.set_len(FILE_SIZE_LIMIT - 100).unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    let file = backend.open(file).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    let err = rt
+        .block_on(
+            tokio::io::write_all(file, vec![1u8; 1000])
+                .and_then(|(file, _)| tokio::io::flush(file)),
+        )
+        .err()
+        .unwrap();
+    // kernel wrote 100 bytes and the rest was submitted again.
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), FILE_SIZE_LIMIT);
+}
+
+#[test]
+fn dropped_file_completes_pending_write() {
+    let backend = IoUring::new(8);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let file = backend.open(open_file(&path)).unwrap();
+
+    let mut rt = Runtime::new().unwrap();
+    // write is only submitted, the file and the ring are dropped before it completes.
+    let (file, _) = rt
+        .block_on(tokio::io::write_all(file, vec![1u8; 1024 * 1024]))
+        .unwrap();
+    drop(file);
+    drop(backend);
+
+    let start = Instant::now();
+    while std::fs::metadata(&path).unwrap().len() < 1024 * 1024 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(std::fs::read(&path).unwrap(), vec![1u8; 1024 * 1024]);
+}
+
+#[test]
+fn full_submission_queue_with_more_files_than_entries() {
+    // one entry, so every file in flight has to wait for the queue.
+    let backend = IoUring::new(1);
+    let dir = tempfile::tempdir().unwrap();
+    let chunks: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 100]).collect();
+
+    let writes: Vec<_> = (0..16)
+        .map(|i| {
+            let file = backend
+                .open(open_file(&dir.path().join(i.to_string())))
+                .unwrap();
+            iter_ok::<_, io::Error>(chunks.clone())
+                .fold(file, |file, chunk| {
+                    tokio::io::write_all(file, chunk).map(|(file, _)| file)
+                })
+                .and_then(tokio::io::flush)
+        })
+        .collect();
+    let mut rt = Runtime::new().unwrap();
+    drop(rt.block_on(join_all(writes)).unwrap());
+
+    let expected = chunks.concat();
+    for i in 0..16 {
+        assert_eq!(
+            std::fs::read(dir.path().join(i.to_string())).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn unsupported_ring_falls_back_to_thread_pool() {
+    // kernel doesn't accept so many entries.
+    let backend = IoUring::new(u32::MAX);
+    assert!(!backend.is_io_uring());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("0");
+    let file = backend.open(open_file(&path)).unwrap();
+    let mut rt = Runtime::new().unwrap();
+    rt.block_on(
+        tokio::io::write_all(file, b"item".to_vec()).and_then(|(file, _)| tokio::io::flush(file)),
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"item".to_vec());
+}